@@ -1,4 +1,4 @@
-use deadpool::managed::{Manager, Object, Pool, QueueMode};
+use deadpool::managed::{Manager, Object, Pool, QueueMode, Timeouts};
 use serde::{Serialize, Serializer};
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -28,6 +28,23 @@ impl<const N: usize> ArrayPool<N> {
             .map(ManagedArray)
             .map_err(|_| Error::Pool)
     }
+
+    /// Gets an array without waiting, failing if the pool is exhausted.
+    ///
+    /// Unlike [`Self::get`], this may be called from synchronous code running on an async runtime.
+    pub fn try_get(&self) -> Result<ManagedArray<N>> {
+        let timeouts = Timeouts {
+            wait: Some(std::time::Duration::ZERO),
+            create: None,
+            recycle: None,
+        };
+
+        // A zero wait timeout makes acquisition non-blocking, and creating or recycling an array
+        // never awaits anything, so this future completes on its first poll.
+        futures::executor::block_on(self.0.timeout_get(&timeouts))
+            .map(ManagedArray)
+            .map_err(|_| Error::Pool)
+    }
 }
 
 #[derive(Debug)]
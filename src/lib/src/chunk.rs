@@ -1,36 +1,41 @@
 use crate::{
     array_pool::{ArrayPool, ManagedArray},
-    LIB_RUNTIME,
+    Hash,
 };
 use once_cell::sync::Lazy;
-use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
-use uuid::Uuid;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 const CHUNK_SIZE: usize = 64_000;
 
 static CHUNK_ARRAYS: Lazy<ArrayPool<CHUNK_SIZE>> = Lazy::new(|| ArrayPool::new(512));
 
-async fn get_chunk_array() -> ManagedArray<CHUNK_SIZE> {
-    CHUNK_ARRAYS
-        .get()
-        .await
-        .expect("array pool did not return new array")
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+/// Up to [`Chunk::SIZE`] bytes of media, identified by their hash.
+///
+/// Only the first `len` bytes are meaningful, and only those are serialized, so short chunks
+/// (such as the last chunk of a file) round-trip exactly and cost no more than their length.
+#[derive(Debug)]
 pub struct Chunk {
-    id: Uuid,
-
-    #[serde(deserialize_with = "deserialize_chunk_array")]
+    hash: Hash,
+    len: usize,
     memory: ManagedArray<CHUNK_SIZE>,
 }
 
 impl Chunk {
     pub const SIZE: usize = CHUNK_SIZE;
 
-    pub async fn new_zeroed(id: Uuid) -> Self {
+    /// # Panics
+    ///
+    /// Panics if `len` exceeds [`Self::SIZE`].
+    pub async fn new_zeroed(hash: Hash, len: usize) -> Self {
+        assert!(len <= Self::SIZE, "chunk length exceeds `Chunk::SIZE`");
+
         Self {
-            id,
+            hash,
+            len,
             memory: CHUNK_ARRAYS
                 .get()
                 .await
@@ -38,48 +43,104 @@ impl Chunk {
         }
     }
 
-    pub fn id(&self) -> Uuid {
-        self.id
+    /// The hash of the chunk's contents, as given when it was created.
+    pub fn hash(&self) -> Hash {
+        self.hash
     }
 }
 
 impl std::ops::Deref for Chunk {
-    type Target = Box<[u8; CHUNK_SIZE]>;
+    type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.memory
+        &self.memory[..self.len]
     }
 }
 
 impl std::ops::DerefMut for Chunk {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.memory
+        &mut self.memory[..self.len]
     }
 }
 
-fn deserialize_chunk_array<'de, D: Deserializer<'de>>(
-    deserializer: D,
-) -> Result<ManagedArray<CHUNK_SIZE>, D::Error> {
-    deserializer.deserialize_bytes(ChunkArrayVisitor)
+impl Serialize for Chunk {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.hash)?;
+        tuple.serialize_element(&ChunkBytes(self))?;
+        tuple.end()
+    }
 }
 
-struct ChunkArrayVisitor;
+impl<'de> Deserialize<'de> for Chunk {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, ChunkVisitor)
+    }
+}
 
-impl Visitor<'_> for ChunkArrayVisitor {
-    type Value = ManagedArray<CHUNK_SIZE>;
+/// Serializes a chunk's bytes as a byte string, so their length is encoded with them.
+struct ChunkBytes<'a>(&'a [u8]);
+
+impl Serialize for ChunkBytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+struct ChunkVisitor;
+
+impl<'de> Visitor<'de> for ChunkVisitor {
+    type Value = Chunk;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "a byte array w/ len {}", CHUNK_SIZE)
+        write!(formatter, "a chunk hash and its bytes")
     }
 
-    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
-        if v.len() != CHUNK_SIZE {
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let hash = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let ChunkMemory { len, memory } = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+
+        Ok(Chunk { hash, len, memory })
+    }
+}
+
+struct ChunkMemory {
+    len: usize,
+    memory: ManagedArray<CHUNK_SIZE>,
+}
+
+impl<'de> Deserialize<'de> for ChunkMemory {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(ChunkMemoryVisitor)
+    }
+}
+
+struct ChunkMemoryVisitor;
+
+impl Visitor<'_> for ChunkMemoryVisitor {
+    type Value = ChunkMemory;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "a byte array w/ len at most {}", CHUNK_SIZE)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        if v.len() > CHUNK_SIZE {
             Err(E::invalid_length(v.len(), &self))
         } else {
-            let mut chunk_array = LIB_RUNTIME.block_on(get_chunk_array());
-            chunk_array.copy_from_slice(v);
-
-            Ok(chunk_array)
+            let mut chunk_array = CHUNK_ARRAYS
+                .try_get()
+                .map_err(|_| E::custom("chunk array pool is exhausted"))?;
+            chunk_array[..v.len()].copy_from_slice(v);
+
+            Ok(ChunkMemory {
+                len: v.len(),
+                memory: chunk_array,
+            })
         }
     }
 }
@@ -0,0 +1,231 @@
+use crate::chunk::Chunk;
+
+/// Decides where a stream of bytes is cut into chunks.
+#[derive(Debug, Clone, Copy)]
+pub enum Chunker {
+    /// Cuts every [`Chunk::SIZE`] bytes.
+    Fixed,
+
+    /// Cuts at content-defined boundaries, so that an edit only changes the chunks around it.
+    FastCdc(FastCdc),
+}
+
+impl Chunker {
+    /// The longest chunk this chunker will produce.
+    pub fn max_len(&self) -> usize {
+        match self {
+            Self::Fixed => Chunk::SIZE,
+            Self::FastCdc(fastcdc) => fastcdc.max,
+        }
+    }
+
+    /// Returns the length of the first chunk of `data`.
+    ///
+    /// `data` must either be at least [`Self::max_len`] bytes long, or be the end of the stream;
+    /// otherwise a later byte could have moved the cut point.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        match self {
+            Self::Fixed => std::cmp::min(data.len(), Chunk::SIZE),
+            Self::FastCdc(fastcdc) => fastcdc.cut(data),
+        }
+    }
+}
+
+/// FastCDC content-defined chunking, with normalized chunking (level 2).
+///
+/// See Xia et al., "FastCDC: a Fast and Efficient Content-Defined Chunking Approach for Data
+/// Deduplication" (USENIX ATC '16).
+#[derive(Debug, Clone, Copy)]
+pub struct FastCdc {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl FastCdc {
+    /// Creates a chunker producing chunks of `min..=max` bytes, averaging roughly `avg` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `0 < min <= avg <= max <= Chunk::SIZE`, and `avg` is at least 64.
+    pub fn new(min: usize, avg: usize, max: usize) -> Self {
+        assert!(0 < min, "minimum chunk size must be non-zero");
+        assert!(min <= avg, "minimum chunk size exceeds the average");
+        assert!(avg <= max, "average chunk size exceeds the maximum");
+        assert!(
+            max <= Chunk::SIZE,
+            "maximum chunk size exceeds `Chunk::SIZE`"
+        );
+        assert!(avg >= 64, "average chunk size is too small");
+
+        // Judge cut points more strictly before the average size, and more loosely after it, to
+        // pull chunk lengths towards the average.
+        let bits = avg.ilog2();
+        let mask_small = u64::MAX << (64 - (bits + 2));
+        let mask_large = u64::MAX << (64 - (bits - 2));
+
+        Self {
+            min,
+            avg,
+            max,
+            mask_small,
+            mask_large,
+        }
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+
+        let end = std::cmp::min(data.len(), self.max);
+        let center = std::cmp::min(self.avg, end);
+
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+
+            let mask = if i < center {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        end
+    }
+}
+
+/// Random values for the gear rolling hash.
+///
+/// These determine every cut point, so changing them breaks deduplication against all
+/// previously stored chunks.
+static GEAR: [u64; 256] = gear_table();
+
+/// Generates the gear table with SplitMix64, from a fixed seed.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x6469_6d65_7365_6364u64;
+
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+
+        i += 1;
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` pseudo-random bytes, the same for every `seed`.
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    /// The lengths of the chunks `chunker` cuts all of `data` into.
+    fn cut_all(chunker: Chunker, mut data: &[u8]) -> Vec<usize> {
+        let mut lens = Vec::new();
+        while !data.is_empty() {
+            let len = chunker.cut(data);
+            lens.push(len);
+            data = &data[len..];
+        }
+
+        lens
+    }
+
+    fn fastcdc() -> Chunker {
+        Chunker::FastCdc(FastCdc::new(8192, 16384, Chunk::SIZE))
+    }
+
+    #[test]
+    fn fixed_cuts_at_chunk_size() {
+        let data = random_bytes(1, 2 * Chunk::SIZE + 10);
+
+        assert_eq!(
+            cut_all(Chunker::Fixed, &data),
+            [Chunk::SIZE, Chunk::SIZE, 10]
+        );
+    }
+
+    #[test]
+    fn fastcdc_respects_bounds() {
+        let data = random_bytes(2, 4 << 20);
+        let lens = cut_all(fastcdc(), &data);
+
+        let (last, lens) = lens.split_last().unwrap();
+        assert!(*last <= Chunk::SIZE);
+        for &len in lens {
+            assert!((8192..=Chunk::SIZE).contains(&len), "chunk of {len} bytes");
+        }
+
+        // Normalized chunking keeps the average close to the one asked for.
+        let avg = data.len() / (lens.len() + 1);
+        assert!((12_000..24_000).contains(&avg), "average of {avg} bytes");
+    }
+
+    #[test]
+    fn fastcdc_cuts_short_data_whole() {
+        let data = random_bytes(3, 8192);
+
+        assert_eq!(fastcdc().cut(&data), data.len());
+        assert_eq!(fastcdc().cut(&data[..100]), 100);
+        assert_eq!(fastcdc().cut(&[]), 0);
+    }
+
+    #[test]
+    fn fastcdc_boundaries_follow_content() {
+        let data = random_bytes(4, 1 << 20);
+        let mut edited = random_bytes(5, 100);
+        edited.extend_from_slice(&data);
+
+        let ends = |data: &[u8]| {
+            cut_all(fastcdc(), data)
+                .into_iter()
+                .scan(0, |end, len| {
+                    *end += len;
+                    Some(*end)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // After the first few chunks, every cut in the edited data falls at the same content as
+        // in the original.
+        let original = ends(&data);
+        let shifted = ends(&edited)
+            .into_iter()
+            .map(|end| end - 100)
+            .collect::<Vec<_>>();
+        let resynced = original.iter().filter(|end| shifted.contains(end)).count();
+
+        assert!(
+            resynced + 3 >= original.len(),
+            "{resynced} of {}",
+            original.len()
+        );
+    }
+}
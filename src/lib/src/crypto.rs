@@ -1,22 +1,24 @@
-use blake2::digest::{Update, VariableOutput};
-use chacha20poly1305::{aead::Buffer, AeadInPlace, XChaCha20Poly1305, XNonce};
-use rand::rngs::OsRng;
-use tokio::io::{AsyncRead, AsyncWrite};
-use x25519_dalek::{EphemeralSecret, PublicKey};
+//! Encryption of frames sent over a [`Connection`](crate::net::Connection).
+//!
+//! Each direction of a connection has its own XChaCha20-Poly1305 key, taken from a completed
+//! [Noise handshake](crate::noise), and its own frame counter. Every frame is sealed under the
+//! next counter value, which is sent along with it, so a frame that is replayed, dropped or
+//! reordered is rejected rather than decrypted. Both sides replace the key in step after a set
+//! number of frames or bytes, so that no key protects too much data, or stays in memory for long
+//! enough to expose the frames before it.
+
+use chacha20poly1305::{aead::Buffer, AeadInPlace, KeyInit, XChaCha20Poly1305, XNonce};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("ECDH handshake write error.")]
-    HandshakeWrite,
-    #[error("ECDH handshake flush error.")]
-    HandshakeFlush,
-    #[error("ECDH handshake read error.")]
-    HandshakeRead,
-    #[error("ECDH handshake finalize error.")]
-    HandshakeFinalize,
-
     #[error("Opaque cipher error.")]
     Cipher,
+
+    #[error("frame {received} was received where frame {expected} was expected")]
+    Replay { expected: u64, received: u64 },
+
+    #[error("frame counter is exhausted")]
+    Exhausted,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -24,62 +26,240 @@ pub type Result<T> = std::result::Result<T, Error>;
 const XCHACHA20_POLY1305_KEY_SIZE: usize = 32;
 pub type Key = [u8; XCHACHA20_POLY1305_KEY_SIZE];
 
-pub async fn ecdh_handshake<R: AsyncRead + AsyncWrite + Unpin>(mut stream: R) -> Result<Key> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-    let private_key = EphemeralSecret::random_from_rng(OsRng);
-    let public_key = PublicKey::from(&private_key);
-
-    // Send public key to peer.
-    stream
-        .write_all(public_key.as_bytes())
-        .await
-        .map_err(|_| Error::HandshakeWrite)?;
-    stream.flush().await.map_err(|_| Error::HandshakeFlush)?;
-
-    // Get public key from peer.
-    let mut peer_public_key = [0u8; size_of::<PublicKey>()];
-    stream
-        .read_exact(&mut peer_public_key)
-        .await
-        .map_err(|_| Error::HandshakeRead)?;
-    let peer_public_key = PublicKey::from(peer_public_key);
-
-    // Calculate shared secret.
-    let dh_secret = private_key.diffie_hellman(&peer_public_key);
-    let mut kdf = blake2::Blake2bVar::new(XCHACHA20_POLY1305_KEY_SIZE).unwrap();
-    kdf.update(dh_secret.as_bytes());
-
-    // Generate encryption key from shared secret.
-    let mut key = [0u8; XCHACHA20_POLY1305_KEY_SIZE];
-    kdf.finalize_variable(&mut key)
-        .map_err(|_| Error::HandshakeFinalize)?;
-
-    Ok(key)
+/// Frames sealed under one key before it is replaced.
+pub const REKEY_FRAMES: u64 = 1 << 20;
+
+/// Bytes sealed under one key before it is replaced.
+pub const REKEY_BYTES: u64 = 1 << 32;
+
+/// The keys for both directions of a connection.
+#[derive(Clone)]
+pub struct Keys {
+    pub send: Key,
+    pub recv: Key,
 }
 
-pub fn encrypt(
-    cipher: &XChaCha20Poly1305,
-    nonce: &XNonce,
-    data: impl AsRef<[u8]>,
-    buffer: &mut dyn Buffer,
-) -> Result<()> {
-    cipher
-        .encrypt_in_place(nonce, data.as_ref(), buffer)
-        .map_err(|_| Error::Cipher)?;
-
-    Ok(())
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keys").finish_non_exhaustive()
+    }
 }
 
-pub fn decrypt(
-    cipher: &XChaCha20Poly1305,
-    nonce: &XNonce,
-    data: impl AsRef<[u8]>,
-    buffer: &mut dyn Buffer,
-) -> Result<()> {
-    cipher
-        .decrypt_in_place(nonce, data.as_ref(), buffer)
-        .map_err(|_| Error::Cipher)?;
-
-    Ok(())
+/// Seals or opens the frames sent in one direction of a connection.
+pub struct Cipher {
+    key: Key,
+    cipher: XChaCha20Poly1305,
+    counter: u64,
+
+    /// Frames and bytes sealed or opened under the current key.
+    frames: u64,
+    bytes: u64,
+}
+
+impl Cipher {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            counter: 0,
+            frames: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Encrypts `buffer` in place as the next frame, and returns the counter to send with it.
+    pub fn seal(&mut self, buffer: &mut dyn Buffer) -> Result<u64> {
+        let counter = self.next_counter()?;
+
+        self.cipher
+            .encrypt_in_place(&nonce(counter), &[], buffer)
+            .map_err(|_| Error::Cipher)?;
+        self.advance(buffer.len());
+
+        Ok(counter)
+    }
+
+    /// Decrypts `buffer` in place, which must be the next frame, sent with `counter`.
+    pub fn open(&mut self, counter: u64, buffer: &mut dyn Buffer) -> Result<()> {
+        if counter != self.counter {
+            return Err(Error::Replay {
+                expected: self.counter,
+                received: counter,
+            });
+        }
+        self.next_counter()?;
+
+        let len = buffer.len();
+        self.cipher
+            .decrypt_in_place(&nonce(counter), &[], buffer)
+            .map_err(|_| Error::Cipher)?;
+        self.advance(len);
+
+        Ok(())
+    }
+
+    /// Takes the counter for the next frame. The largest one is kept for rekeying.
+    fn next_counter(&mut self) -> Result<u64> {
+        let counter = self.counter;
+        if counter == u64::MAX {
+            return Err(Error::Exhausted);
+        }
+        self.counter += 1;
+
+        Ok(counter)
+    }
+
+    /// Counts a frame of `len` sealed bytes, and replaces the key once the thresholds are reached.
+    fn advance(&mut self, len: usize) {
+        self.frames += 1;
+        self.bytes += len as u64;
+
+        if self.frames >= REKEY_FRAMES || self.bytes >= REKEY_BYTES {
+            self.rekey();
+        }
+    }
+
+    /// Replaces the key with one derived from it, as Noise's `REKEY` does: the first bytes of the
+    /// encryption of zeros under the largest nonce, which is never used for a frame.
+    fn rekey(&mut self) {
+        let mut key = vec![0u8; XCHACHA20_POLY1305_KEY_SIZE];
+        self.cipher
+            .encrypt_in_place(&nonce(u64::MAX), &[], &mut key)
+            .expect("rekeying cannot fail");
+        key.truncate(XCHACHA20_POLY1305_KEY_SIZE);
+
+        self.key.copy_from_slice(&key);
+        self.cipher = XChaCha20Poly1305::new(&self.key.into());
+        (self.frames, self.bytes) = (0, 0);
+    }
+}
+
+fn nonce(counter: u64) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..size_of::<u64>()].copy_from_slice(&counter.to_le_bytes());
+
+    XNonce::from(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(cipher: &mut Cipher, frame: &[u8]) -> (u64, Vec<u8>) {
+        let mut buffer = frame.to_vec();
+        let counter = cipher.seal(&mut buffer).unwrap();
+
+        (counter, buffer)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (mut sender, mut receiver) = (Cipher::new([7; 32]), Cipher::new([7; 32]));
+
+        for frame in [&b"first"[..], b"", b"third"] {
+            let (counter, mut buffer) = seal(&mut sender, frame);
+            assert_ne!(buffer, frame);
+
+            receiver.open(counter, &mut buffer).unwrap();
+            assert_eq!(buffer, frame);
+        }
+    }
+
+    #[test]
+    fn tampered_frames_are_rejected() {
+        let (mut sender, mut receiver) = (Cipher::new([7; 32]), Cipher::new([7; 32]));
+
+        let (counter, mut buffer) = seal(&mut sender, b"frame");
+        buffer[0] ^= 1;
+
+        assert!(matches!(
+            receiver.open(counter, &mut buffer),
+            Err(Error::Cipher)
+        ));
+    }
+
+    #[test]
+    fn replayed_and_reordered_frames_are_rejected() {
+        let (mut sender, mut receiver) = (Cipher::new([7; 32]), Cipher::new([7; 32]));
+
+        let first = seal(&mut sender, b"first");
+        let second = seal(&mut sender, b"second");
+
+        // Skipping ahead.
+        let (counter, mut buffer) = second.clone();
+        assert!(matches!(
+            receiver.open(counter, &mut buffer),
+            Err(Error::Replay {
+                expected: 0,
+                received: 1
+            })
+        ));
+
+        let (counter, mut buffer) = first.clone();
+        receiver.open(counter, &mut buffer).unwrap();
+
+        // Replaying.
+        let (counter, mut buffer) = first;
+        assert!(matches!(
+            receiver.open(counter, &mut buffer),
+            Err(Error::Replay {
+                expected: 1,
+                received: 0
+            })
+        ));
+
+        let (counter, mut buffer) = second;
+        receiver.open(counter, &mut buffer).unwrap();
+        assert_eq!(buffer, b"second");
+    }
+
+    #[test]
+    fn both_sides_rekey_in_step() {
+        let (mut sender, mut receiver) = (Cipher::new([7; 32]), Cipher::new([7; 32]));
+        sender.frames = REKEY_FRAMES - 1;
+        receiver.frames = REKEY_FRAMES - 1;
+
+        let (counter, mut buffer) = seal(&mut sender, b"last under the old key");
+        receiver.open(counter, &mut buffer).unwrap();
+        assert_ne!(sender.key, [7; 32]);
+        assert_eq!(sender.key, receiver.key);
+        assert_eq!((sender.frames, sender.bytes), (0, 0));
+
+        // Frames under the new key can't be opened with the old one.
+        let (counter, buffer) = seal(&mut sender, b"first under the new key");
+        let mut stale = Cipher::new([7; 32]);
+        stale.counter = counter;
+        assert!(matches!(
+            stale.open(counter, &mut buffer.clone()),
+            Err(Error::Cipher)
+        ));
+
+        let mut buffer = buffer;
+        receiver.open(counter, &mut buffer).unwrap();
+        assert_eq!(buffer, b"first under the new key");
+    }
+
+    #[test]
+    fn rekeys_after_enough_bytes() {
+        let mut cipher = Cipher::new([7; 32]);
+        // Each sealed frame is 5 bytes, plus a 16 byte tag.
+        cipher.bytes = REKEY_BYTES - 30;
+
+        seal(&mut cipher, b"frame");
+        assert_eq!(cipher.key, [7; 32]);
+
+        seal(&mut cipher, b"frame");
+        assert_ne!(cipher.key, [7; 32]);
+    }
+
+    #[test]
+    fn exhausted_counters_are_refused() {
+        let mut cipher = Cipher::new([7; 32]);
+        cipher.counter = u64::MAX;
+
+        assert!(matches!(
+            cipher.seal(&mut b"frame".to_vec()),
+            Err(Error::Exhausted)
+        ));
+    }
 }
@@ -0,0 +1,71 @@
+//! Lowercase hex encoding of fixed-length byte strings, such as hashes and keys.
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The hex has the wrong number of digits for the bytes expected; holds the number of bytes
+    /// it would make.
+    #[error("hex is {0} bytes long")]
+    Length(usize),
+
+    #[error("hex contains a character that isn't a hex digit")]
+    Digit,
+}
+
+/// Encodes `bytes` as lowercase hex.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decodes exactly `N` bytes from hex, in either case.
+pub fn decode<const N: usize>(s: &str) -> Result<[u8; N], Error> {
+    if s.len() != N * 2 {
+        return Err(Error::Length(s.len() / 2));
+    }
+
+    // Unlike `u8::from_str_radix`, this doesn't accept a sign.
+    let digit = |digit: u8| {
+        char::from(digit)
+            .to_digit(16)
+            .map(|digit| digit as u8)
+            .ok_or(Error::Digit)
+    };
+
+    let mut bytes = [0u8; N];
+    for (byte, digits) in bytes.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        *byte = digit(digits[0])? << 4 | digit(digits[1])?;
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let bytes = [0x00, 0x01, 0x7f, 0x80, 0xab, 0xff];
+
+        assert_eq!(encode(&bytes), "00017f80abff");
+        assert_eq!(decode("00017f80abff"), Ok(bytes));
+        assert_eq!(decode("00017F80ABFF"), Ok(bytes));
+        assert_eq!(decode::<0>(""), Ok([]));
+    }
+
+    #[test]
+    fn checks_length() {
+        assert_eq!(decode::<2>("ab"), Err(Error::Length(1)));
+        assert_eq!(decode::<2>("abcdef"), Err(Error::Length(3)));
+        assert_eq!(decode::<2>("abc"), Err(Error::Length(1)));
+    }
+
+    #[test]
+    fn checks_digits() {
+        for s in ["zz00", "+f00", "-100", " f00", "0x00"] {
+            assert_eq!(decode::<2>(s), Err(Error::Digit), "{s:?}");
+        }
+
+        // Multi-byte characters, even where the total length is right.
+        assert_eq!(decode::<2>("é00"), Err(Error::Digit));
+    }
+}
@@ -1,36 +1,105 @@
-use once_cell::sync::Lazy;
-use tokio::runtime::{Builder, Runtime};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 use uuid::Uuid;
 
 pub mod chunk;
-// pub mod crypto;
+pub mod chunker;
+pub mod crypto;
+pub mod hex;
 // pub mod error;
 pub mod array_pool;
 pub mod net;
+pub mod noise;
 // pub mod buf;
 pub mod bstr;
 
-#[macro_use]
-extern crate tracing;
-
-static LIB_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
-    Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .expect("failed to build detached async runtime")
-});
-
 pub const AGENT_STRING_MAX_LEN: usize = 32;
 
+/// The SHA-256 hash of a chunk's contents, which identifies it.
 #[repr(transparent)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Hash([u8; 16]);
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash, Serialize, Deserialize,
+)]
+pub struct Hash([u8; Hash::LEN]);
 
 impl Hash {
+    pub const LEN: usize = 32;
+
+    /// Hashes `data`.
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
     #[inline]
-    pub const fn into_bytes(self) -> [u8; 16] {
+    pub const fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self(bytes)
+    }
+
+    #[inline]
+    pub const fn into_bytes(self) -> [u8; Self::LEN] {
         self.0
     }
+
+    #[inline]
+    pub const fn as_bytes(&self) -> &[u8; Self::LEN] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HashError {
+    #[error("hash must be {expected} bytes, not {0}", expected = Hash::LEN)]
+    Length(usize),
+
+    #[error("hash is not valid hex")]
+    Hex,
+}
+
+impl TryFrom<&[u8]> for Hash {
+    type Error = HashError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| HashError::Length(bytes.len()))
+    }
+}
+
+impl TryFrom<Vec<u8>> for Hash {
+    type Error = HashError;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(bytes.as_slice())
+    }
+}
+
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+impl std::str::FromStr for Hash {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s).map(Self).map_err(|err| match err {
+            hex::Error::Length(len) => HashError::Length(len),
+            hex::Error::Digit => HashError::Hex,
+        })
+    }
+}
+
+/// Where a shard sits in the cluster's failure hierarchy, from broadest to narrowest. Shards that
+/// share a label at some level can fail together, e.g. by losing the same power feed, switch or
+/// disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureDomain {
+    pub zone: Option<String>,
+    pub rack: Option<String>,
+    pub host: Option<String>,
 }
 
 #[derive(Debug)]
@@ -38,15 +107,17 @@ pub struct ShardInfo {
     id: Uuid,
     agent: String,
     chunks: i64,
+    domain: FailureDomain,
 }
 
 impl ShardInfo {
     #[inline]
-    pub fn new(id: Uuid, agent: String, chunks: u32) -> Self {
+    pub fn new(id: Uuid, agent: String, chunks: u64, domain: FailureDomain) -> Self {
         Self {
             id,
             agent,
-            chunks: chunks.into(),
+            chunks: chunks.try_into().unwrap_or(i64::MAX),
+            domain,
         }
     }
 
@@ -64,6 +135,11 @@ impl ShardInfo {
     pub fn chunks(&self) -> i64 {
         self.chunks
     }
+
+    #[inline]
+    pub fn domain(&self) -> &FailureDomain {
+        &self.domain
+    }
 }
 
 pub fn split_exact<const N: usize>(slice: &[u8]) -> Option<(&[u8; N], &[u8])> {
@@ -75,3 +151,16 @@ pub fn split_exact<const N: usize>(slice: &[u8]) -> Option<(&[u8; N], &[u8])> {
         (unsafe { slice_n.try_into().unwrap_unchecked() }, slice)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_hex_round_trips() {
+        let hash = Hash::of(b"chunk");
+
+        assert_eq!(hash.to_string().parse::<Hash>().unwrap(), hash);
+        assert!(matches!("ab".parse::<Hash>(), Err(HashError::Length(1))));
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{bstr::BStr, chunk::Chunk};
-use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite};
+use crate::{
+    bstr::BStr,
+    chunk::Chunk,
+    crypto::{self, Cipher, Keys},
+    FailureDomain, Hash,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use uuid::Uuid;
 
 #[derive(thiserror::Error, Debug)]
@@ -11,6 +16,12 @@ pub enum Error {
     #[error("bincode error")]
     Coding(#[from] bincode::Error),
 
+    #[error("frame could not be encrypted or decrypted")]
+    Crypto(#[from] crypto::Error),
+
+    #[error("frame of {0} bytes is too long")]
+    FrameLength(u64),
+
     #[error("agent string is not valid UTF-8")]
     MessageAgentInvalidUtf8(#[from] std::str::Utf8Error),
 
@@ -20,29 +31,98 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The longest encrypted frame accepted, far more than any message needs.
+pub const MAX_FRAME_LEN: u64 = 1 << 24;
+
+/// Identifies a request on a connection, so that its response can be told apart from those of
+/// other requests in flight; a response carries the ID of the request it answers.
+pub type RequestId = u64;
+
+/// The request ID of every frame sent while a connection is set up, which happens strictly one
+/// message at a time.
+pub const SETUP_ID: RequestId = 0;
+
+/// A connection between the server and a shard, over which every frame is encrypted with the keys
+/// from their handshake.
 pub struct Connection<IO: AsyncRead + AsyncWrite + Unpin> {
-    stream: IO,
-    recv_buf: Vec<u8>,
-    send_buf: Vec<u8>,
+    recv_half: RecvHalf<ReadHalf<IO>>,
+    send_half: SendHalf<WriteHalf<IO>>,
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin> Connection<IO> {
-    pub fn new(stream: IO) -> Self {
+    pub fn new(stream: IO, keys: Keys) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+
         Self {
-            stream,
-            recv_buf: Vec::new(),
-            send_buf: Vec::new(),
+            recv_half: RecvHalf {
+                stream: reader,
+                buf: Vec::new(),
+                cipher: Cipher::new(keys.recv),
+            },
+            send_half: SendHalf {
+                stream: writer,
+                buf: Vec::new(),
+                cipher: Cipher::new(keys.send),
+            },
         }
     }
 
+    /// Sends `message` as part of setting up the connection.
     pub async fn send(&mut self, message: Message, flush: bool) -> Result<()> {
+        self.send_half.send(SETUP_ID, message, flush).await
+    }
+
+    /// Receives the next message while setting up the connection.
+    pub async fn recv(&mut self) -> Result<Message> {
+        let (_, message) = self.recv_half.recv().await?;
+
+        Ok(message)
+    }
+
+    /// Sends a [`Message::Hello`], or the refusal of one. Unlike every later frame, it carries no
+    /// request ID, in any protocol version, so that peers that speak different versions can still
+    /// read each other's.
+    pub async fn send_hello(&mut self, message: Message) -> Result<()> {
+        self.send_half.send_frame(&message, true).await
+    }
+
+    /// Receives a [`Message::Hello`], or the refusal of one, as sent by
+    /// [`send_hello`](Self::send_hello).
+    pub async fn recv_hello(&mut self) -> Result<Message> {
+        self.recv_half.recv_frame().await
+    }
+
+    /// Splits the connection, once it is set up, so that frames can be sent while others are
+    /// received.
+    pub fn split(self) -> (RecvHalf<ReadHalf<IO>>, SendHalf<WriteHalf<IO>>) {
+        (self.recv_half, self.send_half)
+    }
+}
+
+/// The sending half of a [`Connection`].
+pub struct SendHalf<W: AsyncWrite + Unpin> {
+    stream: W,
+    buf: Vec<u8>,
+    cipher: Cipher,
+}
+
+impl<W: AsyncWrite + Unpin> SendHalf<W> {
+    /// Sends `message` as a frame of the request `id`.
+    pub async fn send(&mut self, id: RequestId, message: Message, flush: bool) -> Result<()> {
+        self.send_frame(&(id, &message), flush).await
+    }
+
+    async fn send_frame(&mut self, frame: &impl Serialize, flush: bool) -> Result<()> {
         use tokio::io::AsyncWriteExt;
 
-        bincode::serialize_into(&mut self.send_buf, &message)?;
+        self.buf.clear();
+        bincode::serialize_into(&mut self.buf, frame)?;
+        let counter = self.cipher.seal(&mut self.buf)?;
 
-        let message_len = self.send_buf.len().try_into().unwrap();
+        let message_len = self.buf.len().try_into().unwrap();
         self.stream.write_u64_le(message_len).await?;
-        self.stream.write_all(&self.send_buf).await?;
+        self.stream.write_u64_le(counter).await?;
+        self.stream.write_all(&self.buf).await?;
 
         if flush {
             self.stream.flush().await?;
@@ -50,34 +130,417 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Connection<IO> {
 
         Ok(())
     }
+}
 
-    pub async fn recv(&mut self) -> Result<Message> {
+/// The receiving half of a [`Connection`].
+pub struct RecvHalf<R: AsyncRead + Unpin> {
+    stream: R,
+    buf: Vec<u8>,
+    cipher: Cipher,
+}
+
+impl<R: AsyncRead + Unpin> RecvHalf<R> {
+    /// Receives the next frame, and returns the ID of the request it belongs to along with its
+    /// message.
+    pub async fn recv(&mut self) -> Result<(RequestId, Message)> {
+        self.recv_frame().await
+    }
+
+    async fn recv_frame<T: DeserializeOwned>(&mut self) -> Result<T> {
         use tokio::io::AsyncReadExt;
 
         let message_len = self.stream.read_u64_le().await?;
-        self.recv_buf.resize(message_len.try_into().unwrap(), 0);
+        if message_len > MAX_FRAME_LEN {
+            return Err(Error::FrameLength(message_len));
+        }
+        let counter = self.stream.read_u64_le().await?;
+        self.buf.resize(message_len.try_into().unwrap(), 0);
 
-        self.stream.read_exact(&mut self.recv_buf).await?;
-        let message = bincode::deserialize(&self.recv_buf)?;
+        self.stream.read_exact(&mut self.buf).await?;
+        self.cipher.open(counter, &mut self.buf)?;
+        let frame = bincode::deserialize(&self.buf)?;
 
-        Ok(message)
+        Ok(frame)
     }
 }
 
-#[repr(u32)]
+/// The oldest protocol version this build can speak.
+///
+/// Version 1 sent every message alone in its frame; version 2 sends each with the ID of the
+/// request it belongs to. The hello exchange is the same in both.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// The newest protocol version this build can speak.
+pub const MAX_PROTOCOL_VERSION: u16 = 2;
+
+/// Optional protocol features, which a connection only uses if both sides support them.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const COMPRESSION: Self = Self(1 << 0);
+    pub const BATCHING: Self = Self(1 << 1);
+    pub const PARTIAL_READS: Self = Self(1 << 2);
+    pub const VARIABLE_CHUNK_LENGTHS: Self = Self(1 << 3);
+
+    const NAMES: [(Self, &'static str); 4] = [
+        (Self::COMPRESSION, "compression"),
+        (Self::BATCHING, "batching"),
+        (Self::PARTIAL_READS, "partial reads"),
+        (Self::VARIABLE_CHUNK_LENGTHS, "variable chunk lengths"),
+    ];
+
+    /// The capabilities this build supports.
+    pub const SUPPORTED: Self = Self(Self::BATCHING.0 | Self::VARIABLE_CHUNK_LENGTHS.0);
+
+    #[inline]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities in both `self` and `other`.
+    #[inline]
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl std::fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| name);
+
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/// The most chunks or hashes a batch request may carry, which keeps its frame well below
+/// [`MAX_FRAME_LEN`].
+pub const MAX_BATCH_LEN: usize = 128;
+
+/// Whether bit `index` of `bitmap` is set, as in [`Message::ShardExistsBatch`].
+#[inline]
+pub fn bit(bitmap: &[u8], index: usize) -> bool {
+    bitmap
+        .get(index / 8)
+        .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+}
+
+/// Packs `bits` into a bitmap, the first into the lowest bit of the first byte.
+pub fn bitmap(bits: impl IntoIterator<Item = bool>) -> Vec<u8> {
+    let mut bitmap = Vec::new();
+
+    for (index, bit) in bits.into_iter().enumerate() {
+        if index % 8 == 0 {
+            bitmap.push(0);
+        }
+        if bit {
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    bitmap
+}
+
+/// Picks the highest protocol version in both `min..=max` and the versions this build speaks.
+pub fn negotiate_version(min: u16, max: u16) -> Option<u16> {
+    let version = std::cmp::min(max, MAX_PROTOCOL_VERSION);
+
+    (version >= std::cmp::max(min, MIN_PROTOCOL_VERSION)).then_some(version)
+}
+
+/// Variants are encoded by their position, so new ones go at the end, and none are ever removed
+/// or reordered. [`Message::Hello`] in particular must keep its position, since it is how peers
+/// learn whether they can understand each other at all.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
-    Ok = 0x0,
+    Ok,
+
+    Ping,
+    Pong,
+
+    AssignId {
+        id: Uuid,
+    },
+
+    ServerInfo {
+        agent: BStr<64>,
+    },
+    ShardInfo {
+        chunks: u64,
+        agent: BStr<64>,
+        labels: Labels,
+    },
+
+    ShardStore {
+        chunk: Chunk,
+    },
+    ShardRetrieve {
+        hash: Hash,
+    },
+    ShardChunkExists {
+        hash: Hash,
+    },
+
+    ShardChunk {
+        chunk: Chunk,
+    },
+
+    ShardDelete {
+        hash: Hash,
+    },
+
+    /// The chunks a shard stores whose contents no longer match their hash, as asked for by
+    /// [`Message::ShardListCorrupt`].
+    ShardCorrupt {
+        hashes: Vec<Hash>,
+    },
+
+    /// Sent in place of a request's usual response when it could not be carried out.
+    Error {
+        error: RequestError,
+    },
+
+    /// Whether the chunk asked about by [`Message::ShardChunkExists`] is stored.
+    ShardExists {
+        exists: bool,
+    },
+
+    /// Sent by a shard as soon as it connects: the ID it was assigned when it first registered,
+    /// or `None` if it has never registered. The server answers with [`Message::Ok`] to accept
+    /// the ID, or [`Message::AssignId`] to give the shard one.
+    ShardIdentify {
+        id: Option<Uuid>,
+    },
+
+    /// Sent by a shard as soon as the connection is encrypted, with the protocol versions and
+    /// capabilities it supports. The server answers with the highest version both support as
+    /// both `min_version` and `max_version`, and the capabilities both support, or with
+    /// [`RequestError::Incompatible`] if they share no version. Both are sent with
+    /// [`Connection::send_hello`], which is the same in every version.
+    Hello {
+        min_version: u16,
+        max_version: u16,
+        capabilities: Capabilities,
+    },
+
+    /// Stores every one of `chunks`, or none of them; answered with [`Message::Ok`]. Only sent
+    /// to shards that support [`Capabilities::BATCHING`], as are the other batch requests.
+    ShardStoreBatch {
+        chunks: Vec<Chunk>,
+    },
+
+    /// Retrieves the chunks `hashes`; answered with [`Message::ShardChunkBatch`].
+    ShardRetrieveBatch {
+        hashes: Vec<Hash>,
+    },
+
+    /// Asks which of the chunks `hashes` are stored; answered with [`Message::ShardExistsBatch`].
+    ShardChunkExistsBatch {
+        hashes: Vec<Hash>,
+    },
+
+    /// The chunks asked for by [`Message::ShardRetrieveBatch`], in the same order, each `None`
+    /// if it is not stored.
+    ShardChunkBatch {
+        chunks: Vec<Option<Chunk>>,
+    },
+
+    /// A bitmap of which chunks asked about by [`Message::ShardChunkExistsBatch`] are stored,
+    /// as read by [`bit`].
+    ShardExistsBatch {
+        bitmap: Vec<u8>,
+    },
+
+    /// Asks which chunks the shard has found corrupt; answered with [`Message::ShardCorrupt`].
+    /// The shard keeps reporting each until the server acknowledges it with
+    /// [`Message::ShardCorruptHandled`].
+    ShardListCorrupt,
+
+    /// Tells the shard the server has dealt with the corrupt chunks `hashes`, so it can stop
+    /// reporting them; answered with [`Message::Ok`].
+    ShardCorruptHandled {
+        hashes: Vec<Hash>,
+    },
+}
+
+/// Why a request could not be carried out, as sent in [`Message::Error`].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestError {
+    #[error("chunk is not stored")]
+    NotFound,
+
+    #[error("chunk contents do not match its hash")]
+    HashMismatch,
+
+    #[error("shard has no room for more chunks")]
+    Full,
+
+    #[error("request is not valid at this point in the connection")]
+    Unexpected,
+
+    #[error("another shard is connected with the same ID")]
+    DuplicateId,
+
+    #[error("shard is not enrolled, or its key belongs to another shard")]
+    Unauthorized,
+
+    #[error("shard storage failed")]
+    Storage,
+
+    #[error("no protocol version is supported by both sides")]
+    Incompatible,
+
+    #[error("batch is longer than `MAX_BATCH_LEN`")]
+    BatchTooLong,
+}
+
+/// The longest a [`FailureDomain`] label may be, in bytes.
+pub const MAX_LABEL_LEN: usize = 64;
+
+/// A shard's [`FailureDomain`] labels, as sent in [`Message::ShardInfo`]. Empty labels are unset.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Labels {
+    pub zone: BStr<MAX_LABEL_LEN>,
+    pub rack: BStr<MAX_LABEL_LEN>,
+    pub host: BStr<MAX_LABEL_LEN>,
+}
 
-    Ping = 0x1,
-    Pong = 0x2,
+impl Labels {
+    /// # Panics
+    ///
+    /// Panics if a label is longer than [`MAX_LABEL_LEN`].
+    pub fn new(domain: &FailureDomain) -> Self {
+        let label = |label: &Option<String>| BStr::new(label.as_deref().unwrap_or_default());
 
-    AssignId { id: Uuid } = 0x3,
+        Self {
+            zone: label(&domain.zone),
+            rack: label(&domain.rack),
+            host: label(&domain.host),
+        }
+    }
 
-    ServerInfo { agent: BStr<64> } = 0x40,
-    ShardInfo { chunks: u64, agent: BStr<64> } = 0x41,
+    pub fn domain(&self) -> FailureDomain {
+        let label = |label: &str| Some(label.to_string()).filter(|label| !label.is_empty());
 
-    ShardStore { chunk: Chunk } = 0x100,
-    ShardRetrieve { id: Uuid } = 0x101,
-    ShardChunkExists { id: Uuid } = 0x102,
+        FailureDomain {
+            zone: label(&self.zone),
+            rack: label(&self.rack),
+            host: label(&self.host),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The position each variant is encoded by, which must never change.
+    fn position(message: &Message) -> u32 {
+        let encoded = bincode::serialize(message).unwrap();
+
+        u32::from_le_bytes(encoded[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn message_positions_are_stable() {
+        let hash = Hash::from_bytes([0; Hash::LEN]);
+
+        assert_eq!(position(&Message::Ok), 0);
+        assert_eq!(position(&Message::AssignId { id: Uuid::nil() }), 3);
+        assert_eq!(position(&Message::ShardChunkExists { hash }), 8);
+        assert_eq!(position(&Message::ShardDelete { hash }), 10);
+        assert_eq!(
+            position(&Message::Error {
+                error: RequestError::NotFound
+            }),
+            12
+        );
+        assert_eq!(
+            position(&Message::Hello {
+                min_version: 1,
+                max_version: 1,
+                capabilities: Capabilities::SUPPORTED,
+            }),
+            15
+        );
+        assert_eq!(
+            position(&Message::ShardExistsBatch { bitmap: Vec::new() }),
+            20
+        );
+        assert_eq!(
+            position(&Message::ShardCorruptHandled { hashes: Vec::new() }),
+            22
+        );
+    }
+
+    #[test]
+    fn negotiates_highest_shared_version() {
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION),
+            Some(MAX_PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_version(0, u16::MAX), Some(MAX_PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate_version(0, MIN_PROTOCOL_VERSION),
+            Some(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(MAX_PROTOCOL_VERSION, u16::MAX),
+            Some(MAX_PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn refuses_disjoint_versions() {
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION - 1), None);
+        assert_eq!(negotiate_version(MAX_PROTOCOL_VERSION + 1, u16::MAX), None);
+
+        // A peer whose range is empty speaks nothing.
+        assert_eq!(
+            negotiate_version(MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION - 1),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn hello_and_requests_round_trip() -> Result<()> {
+        let (a, b) = tokio::io::duplex(1024);
+        let keys = Keys {
+            send: [1; 32],
+            recv: [2; 32],
+        };
+        let mut a = Connection::new(a, keys.clone());
+        let mut b = Connection::new(
+            b,
+            Keys {
+                send: keys.recv,
+                recv: keys.send,
+            },
+        );
+
+        let hello = Message::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: MAX_PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        a.send_hello(hello).await?;
+        assert!(matches!(
+            b.recv_hello().await?,
+            Message::Hello {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: MAX_PROTOCOL_VERSION,
+                ..
+            }
+        ));
+
+        let (mut recv_half, _) = b.split();
+        let (_, mut send_half) = a.split();
+        send_half.send(7, Message::Ping, true).await?;
+        assert!(matches!(recv_half.recv().await?, (7, Message::Ping)));
+
+        Ok(())
+    }
 }
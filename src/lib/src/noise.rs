@@ -0,0 +1,327 @@
+//! Mutual authentication of shards and the server, with the Noise XX handshake.
+//!
+//! Each side holds a long-term static X25519 keypair. The shard initiates, and learns the
+//! server's static key before revealing its own, so it can refuse to go on with a server other
+//! than the one it expects. Its last handshake message carries a payload (such as an enrollment
+//! token) that only the server it expects can read. Once the handshake completes, each side knows
+//! that the other holds the private half of the static key it presented; the server must still
+//! check that key against the shards it has enrolled.
+//!
+//! The handshake also yields the keys that [`crypto`](crate::crypto) encrypts every later frame
+//! with.
+
+use crate::{crypto::Keys, hex};
+use serde::{Deserialize, Serialize};
+use snow::{params::NoiseParams, Builder, HandshakeState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The Noise protocol the handshake follows.
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The longest a Noise message may be.
+const MAX_MESSAGE_LEN: usize = 65535;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error")]
+    Io(#[from] tokio::io::Error),
+
+    #[error("Noise protocol error")]
+    Noise(#[from] snow::Error),
+
+    #[error("peer presented the static key {0}, which is not the one expected")]
+    UnexpectedKey(PublicKey),
+
+    #[error("key must be {expected} bytes, not {0}", expected = PublicKey::LEN)]
+    KeyLength(usize),
+
+    #[error("key is not valid hex")]
+    KeyHex,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A static X25519 public key, which identifies one side of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, std::hash::Hash, Serialize, Deserialize)]
+pub struct PublicKey([u8; PublicKey::LEN]);
+
+impl PublicKey {
+    pub const LEN: usize = 32;
+
+    #[inline]
+    pub const fn as_bytes(&self) -> &[u8; Self::LEN] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| Error::KeyLength(bytes.len()))
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+impl std::str::FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_hex(s).map(Self)
+    }
+}
+
+/// A long-term static X25519 keypair.
+#[derive(Clone)]
+pub struct Keypair {
+    private: [u8; PublicKey::LEN],
+    public: PublicKey,
+}
+
+impl Keypair {
+    pub fn generate() -> Result<Self> {
+        let keypair = builder().generate_keypair()?;
+
+        Self::from_bytes(&keypair.private, &keypair.public)
+    }
+
+    pub fn from_bytes(private: &[u8], public: &[u8]) -> Result<Self> {
+        Ok(Self {
+            private: private
+                .try_into()
+                .map_err(|_| Error::KeyLength(private.len()))?,
+            public: PublicKey::try_from(public)?,
+        })
+    }
+
+    /// Reads a keypair written by [`Keypair::to_hex`].
+    pub fn from_hex(private: &str, public: &str) -> Result<Self> {
+        Ok(Self {
+            private: parse_hex(private)?,
+            public: public.parse()?,
+        })
+    }
+
+    /// Writes the private and public keys as hex.
+    pub fn to_hex(&self) -> (String, String) {
+        (hex::encode(&self.private), self.public.to_string())
+    }
+
+    #[inline]
+    pub fn private(&self) -> &[u8; PublicKey::LEN] {
+        &self.private
+    }
+
+    #[inline]
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+}
+
+impl std::fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a completed handshake established about the other side.
+#[derive(Debug)]
+pub struct Session {
+    /// The static key the other side proved it holds.
+    pub remote: PublicKey,
+
+    /// The payload the initiator sent with its last message; empty for the initiator itself.
+    pub payload: Vec<u8>,
+
+    /// The keys to encrypt the connection with from now on.
+    pub keys: Keys,
+}
+
+/// Runs the handshake as the initiator, failing unless the responder presents the static key
+/// `expected`. `payload` is sent to the responder once it has been authenticated.
+pub async fn initiate<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    local: &Keypair,
+    expected: PublicKey,
+    payload: &[u8],
+) -> Result<Session> {
+    let mut handshake = builder()
+        .local_private_key(local.private())
+        .build_initiator()?;
+
+    // -> e
+    write_message(stream, &mut handshake, &[]).await?;
+
+    // <- e, ee, s, es
+    read_message(stream, &mut handshake).await?;
+    let remote = remote_key(&handshake)?;
+    if remote != expected {
+        return Err(Error::UnexpectedKey(remote));
+    }
+
+    // -> s, se
+    write_message(stream, &mut handshake, payload).await?;
+
+    let (send, recv) = handshake.dangerously_get_raw_split();
+
+    Ok(Session {
+        remote,
+        payload: Vec::new(),
+        keys: Keys { send, recv },
+    })
+}
+
+/// Runs the handshake as the responder. The initiator is authenticated as holding the static key
+/// it presents, but whether that key is allowed is for the caller to decide.
+pub async fn respond<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    local: &Keypair,
+) -> Result<Session> {
+    let mut handshake = builder()
+        .local_private_key(local.private())
+        .build_responder()?;
+
+    // -> e
+    read_message(stream, &mut handshake).await?;
+
+    // <- e, ee, s, es
+    write_message(stream, &mut handshake, &[]).await?;
+
+    // -> s, se
+    let payload = read_message(stream, &mut handshake).await?;
+
+    // The initiator sends with the first key, and the responder with the second.
+    let (recv, send) = handshake.dangerously_get_raw_split();
+
+    Ok(Session {
+        remote: remote_key(&handshake)?,
+        payload,
+        keys: Keys { send, recv },
+    })
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(
+        PATTERN
+            .parse::<NoiseParams>()
+            .expect("Noise pattern is valid"),
+    )
+}
+
+fn remote_key(handshake: &HandshakeState) -> Result<PublicKey> {
+    let remote = handshake.get_remote_static().ok_or(snow::Error::Input)?;
+
+    PublicKey::try_from(remote)
+}
+
+/// Writes the next handshake message, prefixed with its length.
+async fn write_message<IO: AsyncWrite + Unpin>(
+    stream: &mut IO,
+    handshake: &mut HandshakeState,
+    payload: &[u8],
+) -> Result<()> {
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    let len = handshake.write_message(payload, &mut message)?;
+
+    stream.write_u16(len as u16).await?;
+    stream.write_all(&message[..len]).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Reads the next handshake message, and returns its payload.
+async fn read_message<IO: AsyncRead + Unpin>(
+    stream: &mut IO,
+    handshake: &mut HandshakeState,
+) -> Result<Vec<u8>> {
+    let len = stream.read_u16().await?;
+
+    let mut message = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut message).await?;
+
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    let payload_len = handshake.read_message(&message, &mut payload)?;
+    payload.truncate(payload_len);
+
+    Ok(payload)
+}
+
+fn parse_hex(s: &str) -> Result<[u8; PublicKey::LEN]> {
+    hex::decode(s).map_err(|err| match err {
+        hex::Error::Length(len) => Error::KeyLength(len),
+        hex::Error::Digit => Error::KeyHex,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_authenticates_both_sides() -> Result<()> {
+        let shard = Keypair::generate()?;
+        let server = Keypair::generate()?;
+        let (mut a, mut b) = tokio::io::duplex(MAX_MESSAGE_LEN);
+
+        let (initiator, responder) = tokio::join!(
+            initiate(&mut a, &shard, server.public(), b"token"),
+            respond(&mut b, &server),
+        );
+        let (initiator, responder) = (initiator?, responder?);
+
+        assert_eq!(initiator.remote, server.public());
+        assert_eq!(responder.remote, shard.public());
+        assert_eq!(responder.payload, b"token");
+        assert_eq!(initiator.keys.send, responder.keys.recv);
+        assert_eq!(initiator.keys.recv, responder.keys.send);
+        assert_ne!(initiator.keys.send, initiator.keys.recv);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn initiator_rejects_unexpected_server() -> Result<()> {
+        let shard = Keypair::generate()?;
+        let server = Keypair::generate()?;
+        let impostor = Keypair::generate()?;
+        let (mut a, mut b) = tokio::io::duplex(MAX_MESSAGE_LEN);
+
+        let (initiator, responder) = tokio::join!(
+            async move { initiate(&mut a, &shard, server.public(), b"token").await },
+            respond(&mut b, &impostor),
+        );
+
+        assert!(matches!(
+            initiator,
+            Err(Error::UnexpectedKey(key)) if key == impostor.public()
+        ));
+        // The initiator hangs up rather than sending its token to the impostor.
+        assert!(matches!(responder, Err(Error::Io(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keys_round_trip_through_hex() -> Result<()> {
+        let keypair = Keypair::generate()?;
+        let (private, public) = keypair.to_hex();
+
+        let parsed = Keypair::from_hex(&private, &public)?;
+        assert_eq!(parsed.private(), keypair.private());
+        assert_eq!(parsed.public(), keypair.public());
+        assert_eq!(public.parse::<PublicKey>()?, keypair.public());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+CREATE TABLE IF NOT EXISTS shards
+(
+    id UUID PRIMARY KEY,
+    agent TEXT NOT NULL,
+    max_chunks BIGINT NOT NULL,
+    chunks BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS groupings
+(
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS chunk_lookup
+(
+    id UUID PRIMARY KEY,
+    hash BYTEA NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    grouping UUID NOT NULL REFERENCES groupings(id),
+    seq BIGINT NOT NULL,
+    UNIQUE (grouping, seq)
+);
+
+CREATE INDEX IF NOT EXISTS chunk_lookup_hash ON chunk_lookup(hash);
+
+CREATE TABLE IF NOT EXISTS chunk_placement
+(
+    shard_id UUID REFERENCES shards(id),
+    chunk_id UUID REFERENCES chunk_lookup(id),
+    PRIMARY KEY (shard_id, chunk_id)
+);
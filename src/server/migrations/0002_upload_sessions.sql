@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS upload_sessions
+(
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    upload_offset BIGINT NOT NULL,
+    tail BYTEA NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS upload_session_chunks
+(
+    session UUID NOT NULL REFERENCES upload_sessions(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    chunk_id UUID NOT NULL,
+    hash BYTEA NOT NULL,
+    shard_id UUID NOT NULL REFERENCES shards(id),
+    PRIMARY KEY (session, seq)
+);
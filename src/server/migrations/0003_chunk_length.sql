@@ -0,0 +1,13 @@
+-- Chunks are no longer all `Chunk::SIZE` (64 000) bytes long; record the length of each.
+ALTER TABLE chunk_lookup ADD COLUMN IF NOT EXISTS length INTEGER;
+
+UPDATE chunk_lookup l
+SET length = LEAST(64000, g.size - l.seq * 64000)
+FROM groupings g
+WHERE g.id = l.grouping AND l.length IS NULL;
+
+ALTER TABLE chunk_lookup ALTER COLUMN length SET NOT NULL;
+
+ALTER TABLE upload_session_chunks ADD COLUMN IF NOT EXISTS length INTEGER;
+UPDATE upload_session_chunks SET length = 64000 WHERE length IS NULL;
+ALTER TABLE upload_session_chunks ALTER COLUMN length SET NOT NULL;
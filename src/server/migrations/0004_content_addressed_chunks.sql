@@ -0,0 +1,69 @@
+-- Chunks are identified by the SHA-256 hash of their contents, and stored once no matter how
+-- many groupings contain them. Every chunk recorded so far already has its hash, so those with
+-- the same one are merged, and the groupings, upload sessions and placements referring to them
+-- are carried over.
+CREATE TEMPORARY TABLE old_grouping_chunks AS
+SELECT grouping, seq, hash, length, created FROM chunk_lookup;
+
+CREATE TEMPORARY TABLE old_session_chunks AS
+SELECT c.session, c.seq, c.hash, c.length, s.updated AS created
+FROM upload_session_chunks c JOIN upload_sessions s ON s.id = c.session;
+
+CREATE TEMPORARY TABLE old_placements AS
+SELECT p.shard_id, l.hash FROM chunk_placement p JOIN chunk_lookup l ON l.id = p.chunk_id
+UNION
+SELECT shard_id, hash FROM upload_session_chunks;
+
+DROP TABLE upload_session_chunks, chunk_placement, chunk_lookup;
+
+-- `refs` counts the grouping and upload session chunks referring to each chunk.
+CREATE TABLE IF NOT EXISTS chunk_lookup
+(
+    hash BYTEA PRIMARY KEY,
+    length INTEGER NOT NULL,
+    refs BIGINT NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS chunk_placement
+(
+    shard_id UUID REFERENCES shards(id),
+    chunk_hash BYTEA REFERENCES chunk_lookup(hash),
+    PRIMARY KEY (shard_id, chunk_hash)
+);
+
+CREATE TABLE IF NOT EXISTS grouping_chunks
+(
+    grouping UUID NOT NULL REFERENCES groupings(id),
+    seq BIGINT NOT NULL,
+    chunk_hash BYTEA NOT NULL REFERENCES chunk_lookup(hash),
+    PRIMARY KEY (grouping, seq)
+);
+
+CREATE TABLE IF NOT EXISTS upload_session_chunks
+(
+    session UUID NOT NULL REFERENCES upload_sessions(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    chunk_hash BYTEA NOT NULL REFERENCES chunk_lookup(hash),
+    PRIMARY KEY (session, seq)
+);
+
+INSERT INTO chunk_lookup (hash, length, refs, created)
+SELECT hash, max(length), count(*), min(created)
+FROM (
+    SELECT hash, length, created FROM old_grouping_chunks
+    UNION ALL
+    SELECT hash, length, created FROM old_session_chunks
+) chunks
+GROUP BY hash;
+
+INSERT INTO chunk_placement (shard_id, chunk_hash)
+SELECT shard_id, hash FROM old_placements;
+
+INSERT INTO grouping_chunks (grouping, seq, chunk_hash)
+SELECT grouping, seq, hash FROM old_grouping_chunks;
+
+INSERT INTO upload_session_chunks (session, seq, chunk_hash)
+SELECT session, seq, hash FROM old_session_chunks;
+
+DROP TABLE old_grouping_chunks, old_session_chunks, old_placements;
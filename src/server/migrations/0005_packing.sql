@@ -0,0 +1,16 @@
+-- Small media are packed together into shared chunks, so each grouping chunk is now a part of its
+-- chunk: `length` bytes from `chunk_offset`.
+ALTER TABLE grouping_chunks ADD COLUMN IF NOT EXISTS chunk_offset INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE grouping_chunks ADD COLUMN IF NOT EXISTS length INTEGER;
+
+UPDATE grouping_chunks c
+SET length = l.length
+FROM chunk_lookup l
+WHERE l.hash = c.chunk_hash AND c.length IS NULL;
+
+ALTER TABLE grouping_chunks ALTER COLUMN chunk_offset DROP DEFAULT;
+ALTER TABLE grouping_chunks ALTER COLUMN length SET NOT NULL;
+
+ALTER TABLE chunk_lookup ADD COLUMN IF NOT EXISTS packed BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS grouping_chunks_chunk_hash ON grouping_chunks(chunk_hash);
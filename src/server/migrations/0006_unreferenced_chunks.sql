@@ -0,0 +1,7 @@
+-- When each chunk's reference count last reached zero, so that garbage collection can leave
+-- recently unreferenced chunks alone for a grace period.
+ALTER TABLE chunk_lookup ADD COLUMN IF NOT EXISTS unreferenced TIMESTAMP WITH TIME ZONE;
+UPDATE chunk_lookup SET unreferenced = now() WHERE refs = 0 AND unreferenced IS NULL;
+
+CREATE INDEX IF NOT EXISTS chunk_lookup_unreferenced ON chunk_lookup(unreferenced)
+    WHERE refs = 0;
@@ -0,0 +1,3 @@
+-- The number of shards to store each chunk of a resumable upload on, if not the cluster-wide
+-- replication factor.
+ALTER TABLE upload_sessions ADD COLUMN IF NOT EXISTS replicas INTEGER;
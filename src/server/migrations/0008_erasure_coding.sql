@@ -0,0 +1,16 @@
+-- A chunk is either replicated, with each placement holding all of it, or erasure coded into
+-- `data_pieces` data and `parity_pieces` parity pieces, with each placement holding one piece.
+ALTER TABLE chunk_lookup ADD COLUMN IF NOT EXISTS data_pieces SMALLINT;
+ALTER TABLE chunk_lookup ADD COLUMN IF NOT EXISTS parity_pieces SMALLINT;
+
+-- `piece` is NULL for a whole replica. Shards store what a placement holds under its own hash,
+-- which for a replica is the chunk's.
+ALTER TABLE chunk_placement ADD COLUMN IF NOT EXISTS piece SMALLINT;
+ALTER TABLE chunk_placement ADD COLUMN IF NOT EXISTS stored_hash BYTEA;
+UPDATE chunk_placement SET stored_hash = chunk_hash WHERE stored_hash IS NULL;
+ALTER TABLE chunk_placement ALTER COLUMN stored_hash SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS chunk_placement_stored_hash ON chunk_placement(shard_id, stored_hash);
+
+-- The redundancy policy of a resumable upload, if not plain replication.
+ALTER TABLE upload_sessions ADD COLUMN IF NOT EXISTS policy TEXT;
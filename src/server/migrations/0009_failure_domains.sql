@@ -0,0 +1,4 @@
+-- The failure domain labels each shard reported, from broadest to narrowest; any may be unset.
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS zone TEXT;
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS rack TEXT;
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS host TEXT;
@@ -0,0 +1,7 @@
+-- The number of shards each replicated chunk should be kept on: the most any of the uploads that
+-- stored or referenced it asked for. Erasure coded chunks should have every piece stored.
+ALTER TABLE chunk_lookup ADD COLUMN IF NOT EXISTS replicas SMALLINT;
+
+UPDATE chunk_lookup l
+SET replicas = greatest((SELECT count(*) FROM chunk_placement WHERE chunk_hash = l.hash), 1)
+WHERE l.data_pieces IS NULL AND l.replicas IS NULL;
@@ -0,0 +1,11 @@
+-- `online` shards are connected and answering pings, and `offline` ones are not. `draining` shards
+-- are still read from but take no new chunks, and have theirs moved elsewhere; they stay
+-- draining across reconnects, until an operator undrains them.
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS state TEXT NOT NULL DEFAULT 'offline'
+    CHECK (state IN ('online', 'offline', 'draining'));
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS registered TIMESTAMP WITH TIME ZONE NOT NULL
+    DEFAULT now();
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS last_seen TIMESTAMP WITH TIME ZONE;
+
+-- The round trip time of the last ping answered, in microseconds.
+ALTER TABLE shards ADD COLUMN IF NOT EXISTS rtt_micros BIGINT;
@@ -0,0 +1,24 @@
+-- The server's long-term static key, which shards pin; generated on first start.
+CREATE TABLE IF NOT EXISTS server_key
+(
+    singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+    private_key BYTEA NOT NULL,
+    public_key BYTEA NOT NULL
+);
+
+-- The static key each enrolled shard authenticates with. Shards recorded before enrollment
+-- existed have none, and must enroll with a token to reconnect.
+CREATE TABLE IF NOT EXISTS shard_keys
+(
+    shard_id UUID PRIMARY KEY,
+    public_key BYTEA NOT NULL UNIQUE,
+    enrolled TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- One-time tokens that let a shard with a key not yet known enroll. Only their hashes are kept.
+CREATE TABLE IF NOT EXISTS enrollment_tokens
+(
+    token_hash BYTEA PRIMARY KEY,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL
+);
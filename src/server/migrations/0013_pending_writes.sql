@@ -0,0 +1,14 @@
+-- What is being stored on shards ahead of its placement being recorded, and what is being deleted
+-- from shards after its last placement there was removed. Either keeps the other from starting
+-- for the same shard and stored hash, so that a store is never undone by a concurrent delete,
+-- and whatever is stored but never recorded is found and deleted once it has lingered too long.
+CREATE TABLE IF NOT EXISTS pending_writes
+(
+    shard_id UUID NOT NULL REFERENCES shards(id),
+    stored_hash BYTEA NOT NULL,
+    deleting BOOLEAN NOT NULL,
+    updated TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (shard_id, stored_hash)
+);
+
+CREATE INDEX IF NOT EXISTS pending_writes_updated ON pending_writes(updated);
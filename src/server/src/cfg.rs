@@ -1,3 +1,4 @@
+use lib::chunker::{Chunker, FastCdc};
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use std::net::SocketAddr;
@@ -5,8 +6,27 @@ use std::net::SocketAddr;
 #[derive(Debug, Deserialize)]
 pub struct Cfg {
     pub bind: Bind,
+
+    /// Mutual TLS for shard connections; shards connect in plain TCP when unset.
+    #[serde(default)]
+    pub tls: Option<Tls>,
     pub db: Db,
     pub interval: Interval,
+    pub timeout: Timeout,
+    pub liveness: Liveness,
+    pub enrollment: Enrollment,
+
+    /// Access to the admin API; it refuses every request when unset.
+    #[serde(default)]
+    pub admin: Admin,
+    pub chunking: Chunking,
+    pub replication: Replication,
+    pub erasure: Erasure,
+    pub placement: Placement,
+    pub packing: Packing,
+    pub gc: Gc,
+    pub rebalance: Rebalance,
+    pub repair: Repair,
 }
 
 #[derive(Debug, Deserialize)]
@@ -15,6 +35,18 @@ pub struct Bind {
     pub http: SocketAddr,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    /// Path to the PEM certificate chain the server presents to shards.
+    pub cert: String,
+
+    /// Path to the PEM private key of the server's certificate.
+    pub key: String,
+
+    /// Path to the PEM bundle of CA certificates that shard certificates must be issued by.
+    pub ca: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Db {
     pub url: String,
@@ -25,6 +57,143 @@ pub struct Interval {
     pub ping: u64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Timeout {
+    pub message: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Liveness {
+    /// Number of consecutive pings a shard may leave unanswered for a ping interval each, before
+    /// it is marked offline and disconnected.
+    pub missed: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Enrollment {
+    /// Milliseconds an enrollment token may be used for after it is created.
+    pub ttl: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Admin {
+    /// The bearer token every admin request must carry.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkingMode {
+    Fixed,
+    ContentDefined,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Chunking {
+    pub mode: ChunkingMode,
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl Chunking {
+    pub fn chunker(&self) -> Chunker {
+        match self.mode {
+            ChunkingMode::Fixed => Chunker::Fixed,
+            ChunkingMode::ContentDefined => {
+                Chunker::FastCdc(FastCdc::new(self.min, self.avg, self.max))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Replication {
+    /// Number of distinct shards each chunk is stored on, unless overridden for an upload.
+    pub factor: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Erasure {
+    /// Number of data pieces each chunk of an erasure coded upload is split into.
+    pub data: usize,
+
+    /// Number of parity pieces computed for each chunk of an erasure coded upload; this many
+    /// pieces can be lost without losing the chunk.
+    pub parity: usize,
+}
+
+/// A level of the failure hierarchy shards report their place in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainLevel {
+    Shard,
+    Host,
+    Rack,
+    Zone,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Placement {
+    /// No two replicas or pieces of a chunk are placed in the same failure domain at this level.
+    pub domain: DomainLevel,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Packing {
+    /// Media of at most this many bytes are packed together into shared chunks; 0 disables
+    /// packing.
+    pub threshold: usize,
+
+    /// Milliseconds to wait for more media to share a pack with.
+    pub linger: u64,
+
+    pub compaction: Compaction,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Compaction {
+    /// Milliseconds between checks for packs to compact.
+    pub interval: u64,
+
+    /// Packs with less than this percentage of their bytes still referenced are compacted.
+    pub live: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Gc {
+    /// Milliseconds between garbage collection passes.
+    pub interval: u64,
+
+    /// Milliseconds a chunk must have been unreferenced for before it is collected.
+    pub grace: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rebalance {
+    /// Milliseconds between rebalancing passes, besides those started by shards joining or
+    /// leaving.
+    pub interval: u64,
+
+    /// Bytes per second to migrate at, at most.
+    pub rate: u64,
+
+    /// Chunks are only moved off a shard that is at least this many percentage points fuller
+    /// than the one they move to, so that passes settle rather than shuffling chunks back and
+    /// forth.
+    pub threshold: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Repair {
+    /// Milliseconds between checks for under-replicated chunks.
+    pub interval: u64,
+
+    /// Milliseconds a shard must have gone unseen for before what it holds is considered lost,
+    /// and repaired elsewhere.
+    pub timeout: u64,
+}
+
 pub fn get() -> &'static Cfg {
     static APP_CONFIG: Lazy<Cfg> = Lazy::new(|| {
         use config::{Config, Environment};
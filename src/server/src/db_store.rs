@@ -1,6 +1,239 @@
+use crate::redundancy::{ErasureCoding, Policy};
 use anyhow::Result;
-use lib::ShardInfo;
-use sqlx::PgPool;
+use lib::{
+    noise::{Keypair, PublicKey},
+    FailureDomain, Hash, ShardInfo,
+};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use std::{ops::Range, str::FromStr, time::Duration};
+use uuid::Uuid;
+
+/// A chunk of media that has been written to storage.
+#[derive(Debug, Clone)]
+pub struct PlacedChunk {
+    pub hash: Hash,
+    pub length: usize,
+
+    /// The part of the chunk holding the media's bytes: all of it, unless the chunk is a pack.
+    pub range: Range<usize>,
+
+    /// How the chunk is coded, or `None` if it is replicated.
+    pub coding: Option<ErasureCoding>,
+
+    /// Number of shards the chunk should be kept on, if it is replicated.
+    pub replicas: usize,
+
+    /// Where the chunk, or its pieces, were newly stored; empty if it was already stored
+    /// redundantly enough.
+    pub placements: Vec<Placement>,
+}
+
+/// A replica or piece of a chunk, stored on a shard.
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub shard_id: Uuid,
+
+    /// Which piece of an erasure coded chunk this is, or `None` if it is a whole replica.
+    pub piece: Option<usize>,
+
+    /// The hash the shard stores the replica or piece under.
+    pub stored_hash: Hash,
+}
+
+impl Placement {
+    /// A whole replica of the chunk `hash`.
+    pub fn replica(shard_id: Uuid, hash: Hash) -> Self {
+        Self {
+            shard_id,
+            piece: None,
+            stored_hash: hash,
+        }
+    }
+}
+
+/// A stored piece of media.
+#[derive(Debug, sqlx::FromRow)]
+pub struct Grouping {
+    pub content_type: String,
+    pub size: i64,
+}
+
+/// A chunk of a grouping, and the shards it is placed on.
+///
+/// The grouping's bytes are the `length` bytes of the chunk from `offset`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ChunkLocation {
+    #[sqlx(try_from = "Vec<u8>")]
+    pub hash: Hash,
+    pub offset: i32,
+    pub length: i32,
+    pub chunk_length: i32,
+    pub data_pieces: Option<i16>,
+    pub parity_pieces: Option<i16>,
+
+    /// The shards holding a whole replica of the chunk.
+    pub shard_ids: Vec<Uuid>,
+
+    /// The shards holding a piece of the chunk, with which piece each holds and its hash.
+    pub piece_shard_ids: Vec<Uuid>,
+    pub pieces: Vec<i16>,
+    pub piece_hashes: Vec<Vec<u8>>,
+}
+
+/// Whether a shard is reachable, and whether chunks may be placed on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardState {
+    /// Connected and answering pings.
+    Online,
+
+    /// Disconnected, or not answering pings.
+    Offline,
+
+    /// Still read from, but taking no new chunks and having its chunks moved elsewhere.
+    Draining,
+}
+
+impl FromStr for ShardState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(Self::Online),
+            "offline" => Ok(Self::Offline),
+            "draining" => Ok(Self::Draining),
+            _ => bail!("unknown shard state: {s:?}"),
+        }
+    }
+}
+
+/// A shard, as last recorded.
+#[derive(Debug, sqlx::FromRow)]
+pub struct ShardRecord {
+    pub id: Uuid,
+    pub max_chunks: i64,
+    pub chunks: i64,
+    pub zone: Option<String>,
+    pub rack: Option<String>,
+    pub host: Option<String>,
+    pub state: String,
+}
+
+impl ShardRecord {
+    pub fn state(&self) -> Result<ShardState> {
+        self.state.parse()
+    }
+
+    pub fn domain(&self) -> FailureDomain {
+        FailureDomain {
+            zone: self.zone.clone(),
+            rack: self.rack.clone(),
+            host: self.host.clone(),
+        }
+    }
+}
+
+/// A shard's registration and liveness, as shown to operators.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ShardStatus {
+    pub id: Uuid,
+    pub agent: String,
+    pub state: String,
+    pub max_chunks: i64,
+    pub chunks: i64,
+    pub registered: String,
+    pub last_seen: Option<String>,
+    pub rtt_micros: Option<i64>,
+}
+
+/// What is recorded about a chunk, for deciding how to store it again.
+#[derive(Debug, sqlx::FromRow)]
+pub struct RecordedChunk {
+    pub refs: i64,
+    pub data_pieces: Option<i16>,
+    pub parity_pieces: Option<i16>,
+
+    /// The shards holding the chunk, or a piece of it.
+    pub shard_ids: Vec<Uuid>,
+}
+
+/// A referenced chunk, and the shards its replicas or pieces are stored on, with the hash each is
+/// stored under.
+#[derive(Debug, sqlx::FromRow)]
+pub struct ChunkPlacements {
+    #[sqlx(try_from = "Vec<u8>")]
+    pub hash: Hash,
+    pub shard_ids: Vec<Uuid>,
+    pub stored_hashes: Vec<Vec<u8>>,
+}
+
+/// A referenced chunk with fewer live replicas or pieces than it should have, and where the live
+/// ones are.
+#[derive(Debug, sqlx::FromRow)]
+pub struct UnderReplicatedChunk {
+    #[sqlx(flatten)]
+    pub location: ChunkLocation,
+
+    /// Number of shards the chunk should be kept on, if it is replicated.
+    pub replicas: Option<i16>,
+}
+
+/// The outcome of releasing a chunk's placement on a shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Release {
+    /// The placement was left as it is: the chunk was not in the expected state (e.g. it is
+    /// referenced again), or was not placed on the shard.
+    Kept,
+
+    /// The placement was removed; the shard no longer needs what it stores under this hash, and
+    /// its deletion has been recorded, to be finished with [`DbStore::finish_delete`].
+    Delete(Hash),
+
+    /// The placement was removed, but another placement on the shard is stored under the same
+    /// hash, or it is being stored there again.
+    Shared,
+}
+
+/// A chunk, and the shards it is placed on.
+#[derive(Debug, sqlx::FromRow)]
+pub struct StoredChunk {
+    #[sqlx(try_from = "Vec<u8>")]
+    pub hash: Hash,
+    pub shard_ids: Vec<Uuid>,
+}
+
+/// Something being stored on or deleted from a shard, for longer than expected.
+#[derive(Debug, sqlx::FromRow)]
+pub struct PendingWrite {
+    pub shard_id: Uuid,
+    #[sqlx(try_from = "Vec<u8>")]
+    pub stored_hash: Hash,
+    pub deleting: bool,
+}
+
+/// A grouping chunk stored in a pack.
+#[derive(Debug, sqlx::FromRow)]
+pub struct PackedObject {
+    pub grouping: Uuid,
+    pub seq: i64,
+    pub offset: i32,
+    pub length: i32,
+}
+
+/// An in-progress resumable upload.
+#[derive(Debug, sqlx::FromRow)]
+pub struct UploadSession {
+    pub id: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub upload_offset: i64,
+    pub tail: Vec<u8>,
+    pub replicas: Option<i32>,
+    pub policy: Option<String>,
+    pub chunks: i64,
+}
 
 #[derive(Debug)]
 pub struct DbStore {
@@ -14,15 +247,1191 @@ impl DbStore {
     }
 
     pub async fn add_shard(&self, shard: &ShardInfo) -> Result<()> {
-        // query!(
-        //     "INSERT INTO shards VALUES ($1, $2, $3, 0)",
-        //     shard.id(),
-        //     shard.agent(),
-        //     shard.chunks()
-        // )
-        // .execute(&self.pool)
-        // .await?;
+        let domain = shard.domain();
+
+        // A draining shard stays draining when it reconnects.
+        sqlx::query(
+            "INSERT INTO shards
+                (id, agent, max_chunks, chunks, zone, rack, host, state, registered, last_seen)
+             VALUES ($1, $2, $3, 0, $4, $5, $6, 'online', now(), now())
+             ON CONFLICT (id) DO UPDATE SET agent = $2, max_chunks = $3, zone = $4, rack = $5,
+                host = $6, last_seen = now(),
+                state = CASE shards.state WHEN 'draining' THEN 'draining' ELSE 'online' END",
+        )
+        .bind(shard.id())
+        .bind(shard.agent())
+        .bind(shard.chunks())
+        .bind(&domain.zone)
+        .bind(&domain.rack)
+        .bind(&domain.host)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_shards(&self) -> Result<Vec<ShardRecord>> {
+        let shards =
+            sqlx::query_as("SELECT id, max_chunks, chunks, zone, rack, host, state FROM shards")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(shards)
+    }
+
+    /// Returns the server's static key, recording `generated` as the key first if there is none.
+    pub async fn get_server_key(&self, generated: &Keypair) -> Result<Keypair> {
+        sqlx::query(
+            "INSERT INTO server_key (private_key, public_key) VALUES ($1, $2)
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(generated.private().as_slice())
+        .bind(generated.public().as_bytes().as_slice())
+        .execute(&self.pool)
+        .await?;
+
+        let (private, public): (Vec<u8>, Vec<u8>) =
+            sqlx::query_as("SELECT private_key, public_key FROM server_key")
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(Keypair::from_bytes(&private, &public)?)
+    }
+
+    /// Records a one-time enrollment token by its hash, which expires after `ttl`.
+    pub async fn add_enrollment_token(&self, token_hash: Hash, ttl: Duration) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO enrollment_tokens (token_hash, created, expires)
+             VALUES ($1, now(), now() + make_interval(secs => $2))",
+        )
+        .bind(token_hash.as_bytes().as_slice())
+        .bind(ttl.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Decides which shard ID the holder of the static key `key` may connect as, given the ID it
+    /// `presented` (if any).
+    ///
+    /// A key that is enrolled may only connect as the shard it was enrolled for. A key that isn't
+    /// is enrolled if `token_hash` is the hash of an unexpired enrollment token, which is used up,
+    /// for the presented ID if no other key has it, or else for `new_id`. Returns `None` if the
+    /// key may not connect.
+    pub async fn authorize_shard(
+        &self,
+        key: PublicKey,
+        presented: Option<Uuid>,
+        token_hash: Option<Hash>,
+        new_id: Uuid,
+    ) -> Result<Option<Uuid>> {
+        let mut txn = self.pool.begin().await?;
+
+        let enrolled: Option<Uuid> =
+            sqlx::query_scalar("SELECT shard_id FROM shard_keys WHERE public_key = $1")
+                .bind(key.as_bytes().as_slice())
+                .fetch_optional(&mut *txn)
+                .await?;
+
+        if let Some(id) = enrolled {
+            return Ok(presented
+                .is_none_or(|presented| presented == id)
+                .then_some(id));
+        }
+
+        let Some(token_hash) = token_hash else {
+            return Ok(None);
+        };
+
+        let id = presented.unwrap_or(new_id);
+        let enrolled = sqlx::query(
+            "INSERT INTO shard_keys (shard_id, public_key, enrolled) VALUES ($1, $2, now())
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(id)
+        .bind(key.as_bytes().as_slice())
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+
+        let redeemed =
+            sqlx::query("DELETE FROM enrollment_tokens WHERE token_hash = $1 AND expires > now()")
+                .bind(token_hash.as_bytes().as_slice())
+                .execute(&mut *txn)
+                .await?
+                .rows_affected();
+
+        // Only use the token up if it enrolls the shard.
+        if enrolled == 0 || redeemed == 0 {
+            return Ok(None);
+        }
+        txn.commit().await?;
+
+        Ok(Some(id))
+    }
+
+    /// Returns the registration and liveness of every recorded shard.
+    pub async fn get_shard_statuses(&self) -> Result<Vec<ShardStatus>> {
+        let shards = sqlx::query_as(
+            "SELECT id, agent, state, max_chunks, chunks, registered::TEXT, last_seen::TEXT,
+                rtt_micros
+             FROM shards
+             ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(shards)
+    }
+
+    /// Records that the shard `id` answered a ping after `rtt`, bringing it back online if it
+    /// was offline.
+    pub async fn record_pong(&self, id: Uuid, rtt: Duration) -> Result<()> {
+        sqlx::query(
+            "UPDATE shards SET last_seen = now(), rtt_micros = $2,
+                state = CASE state WHEN 'offline' THEN 'online' ELSE state END
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(i64::try_from(rtt.as_micros())?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the shard `id` offline, unless it is draining.
+    pub async fn mark_offline(&self, id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE shards SET state = 'offline' WHERE id = $1 AND state = 'online'")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Marks every online shard offline, since no shard is connected to a server that has just
+    /// started.
+    pub async fn mark_all_offline(&self) -> Result<()> {
+        sqlx::query("UPDATE shards SET state = 'offline' WHERE state = 'online'")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Starts or stops draining the shard `id`. Returns `false` if it is not recorded.
+    pub async fn set_draining(&self, id: Uuid, draining: bool) -> Result<bool> {
+        // An undrained shard is offline until it next answers a ping.
+        let updated = sqlx::query(
+            "UPDATE shards SET state = CASE WHEN $2 THEN 'draining' ELSE 'offline' END
+             WHERE id = $1 AND (state = 'draining') <> $2",
+        )
+        .bind(id)
+        .bind(draining)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        if updated > 0 {
+            return Ok(true);
+        }
+
+        let exists = sqlx::query("SELECT 1 FROM shards WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(exists.is_some())
+    }
+
+    /// Returns the shards that have not been seen for longer than `timeout`.
+    pub async fn get_unseen_shards(&self, timeout: Duration) -> Result<Vec<Uuid>> {
+        let shards = sqlx::query_scalar(
+            "SELECT id FROM shards
+             WHERE coalesce(last_seen, registered) < now() - make_interval(secs => $1)",
+        )
+        .bind(timeout.as_secs_f64())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(shards)
+    }
+
+    /// Returns what is recorded about the chunk `hash`, if anything, so that it need not be
+    /// stored again where it already is.
+    pub async fn get_recorded_chunk(&self, hash: Hash) -> Result<Option<RecordedChunk>> {
+        let chunk = sqlx::query_as(
+            "SELECT l.refs, l.data_pieces, l.parity_pieces,
+                array(SELECT shard_id FROM chunk_placement WHERE chunk_hash = l.hash) AS shard_ids
+             FROM chunk_lookup l
+             WHERE l.hash = $1",
+        )
+        .bind(hash.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(chunk)
+    }
+
+    /// Records a grouping and a reference to each of its chunks, in sequence order.
+    pub async fn add_grouping(
+        &self,
+        id: Uuid,
+        name: &str,
+        content_type: &str,
+        size: u64,
+        chunks: &[PlacedChunk],
+    ) -> Result<()> {
+        let mut txn = self.pool.begin().await?;
+        insert_grouping(&mut txn, id, name, content_type, size).await?;
+        reference_chunks(&mut txn, chunks).await?;
+        insert_grouping_chunks(&mut txn, id, 0, chunks).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_grouping(&self, id: Uuid) -> Result<Option<Grouping>> {
+        let grouping = sqlx::query_as("SELECT content_type, size FROM groupings WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(grouping)
+    }
+
+    /// Returns the location of each chunk of a grouping, in sequence order.
+    pub async fn get_grouping_chunks(&self, id: Uuid) -> Result<Vec<ChunkLocation>> {
+        let chunks = sqlx::query_as(
+            "SELECT c.chunk_hash AS hash, c.chunk_offset AS offset, c.length,
+                l.length AS chunk_length, l.data_pieces, l.parity_pieces,
+                coalesce(array_agg(p.shard_id) FILTER (WHERE p.piece IS NULL), '{}') AS shard_ids,
+                coalesce(array_agg(p.shard_id ORDER BY p.piece, p.shard_id)
+                    FILTER (WHERE p.piece IS NOT NULL), '{}') AS piece_shard_ids,
+                coalesce(array_agg(p.piece ORDER BY p.piece, p.shard_id)
+                    FILTER (WHERE p.piece IS NOT NULL), '{}') AS pieces,
+                coalesce(array_agg(p.stored_hash ORDER BY p.piece, p.shard_id)
+                    FILTER (WHERE p.piece IS NOT NULL), '{}') AS piece_hashes
+             FROM grouping_chunks c
+             JOIN chunk_lookup l ON l.hash = c.chunk_hash
+             LEFT JOIN chunk_placement p ON p.chunk_hash = c.chunk_hash
+             WHERE c.grouping = $1
+             GROUP BY c.seq, c.chunk_hash, c.chunk_offset, c.length, l.hash
+             ORDER BY c.seq",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chunks)
+    }
+
+    /// Deletes a grouping, releasing its references to its chunks. Returns `false` if it did not
+    /// exist.
+    pub async fn delete_grouping(&self, id: Uuid) -> Result<bool> {
+        let mut txn = self.pool.begin().await?;
+
+        // Lock the grouping first, so that its chunks can't change (or be released twice) while
+        // they are being counted.
+        let locked = sqlx::query("SELECT 1 FROM groupings WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *txn)
+            .await?;
+
+        if locked.is_none() {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            "UPDATE chunk_lookup l
+             SET refs = l.refs - c.refs, unreferenced = CASE WHEN l.refs = c.refs THEN now() END
+             FROM (
+                SELECT chunk_hash, count(*) AS refs FROM grouping_chunks
+                WHERE grouping = $1 GROUP BY chunk_hash
+             ) c
+             WHERE l.hash = c.chunk_hash",
+        )
+        .bind(id)
+        .execute(&mut *txn)
+        .await?;
+
+        sqlx::query("DELETE FROM grouping_chunks WHERE grouping = $1")
+            .bind(id)
+            .execute(&mut *txn)
+            .await?;
+
+        sqlx::query("DELETE FROM groupings WHERE id = $1")
+            .bind(id)
+            .execute(&mut *txn)
+            .await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Returns up to `limit` chunks that have been unreferenced for longer than `grace`, oldest
+    /// first.
+    pub async fn get_collectable_chunks(
+        &self,
+        grace: Duration,
+        limit: i64,
+    ) -> Result<Vec<StoredChunk>> {
+        let chunks = sqlx::query_as(
+            "SELECT l.hash,
+                array(SELECT shard_id FROM chunk_placement WHERE chunk_hash = l.hash) AS shard_ids
+             FROM chunk_lookup l
+             WHERE l.refs = 0 AND l.unreferenced < now() - make_interval(secs => $1)
+             ORDER BY l.unreferenced
+             LIMIT $2",
+        )
+        .bind(grace.as_secs_f64())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chunks)
+    }
+
+    /// Removes the placement of the chunk `hash` on `shard_id`, so that it can be deleted from the
+    /// shard, if the chunk is still unreferenced and has been for longer than `grace`.
+    pub async fn release_placement(
+        &self,
+        hash: Hash,
+        shard_id: Uuid,
+        grace: Duration,
+    ) -> Result<Release> {
+        let mut txn = self.pool.begin().await?;
+
+        let locked = sqlx::query(
+            "SELECT 1 FROM chunk_lookup
+             WHERE hash = $1 AND refs = 0 AND unreferenced < now() - make_interval(secs => $2)
+             FOR UPDATE",
+        )
+        .bind(hash.as_bytes().as_slice())
+        .bind(grace.as_secs_f64())
+        .fetch_optional(&mut *txn)
+        .await?;
+
+        if locked.is_none() {
+            return Ok(Release::Kept);
+        }
+
+        let release = delete_placement(&mut txn, hash, shard_id).await?;
+        txn.commit().await?;
+
+        Ok(release)
+    }
+
+    /// Returns the number of chunks that are referenced.
+    pub async fn count_referenced_chunks(&self) -> Result<i64> {
+        let count = sqlx::query_scalar("SELECT count(*) FROM chunk_lookup WHERE refs > 0")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Returns up to `limit` referenced chunks with hashes after `after`, in hash order, and where
+    /// each of their replicas or pieces is stored.
+    pub async fn get_chunk_placements(
+        &self,
+        after: Option<Hash>,
+        limit: i64,
+    ) -> Result<Vec<ChunkPlacements>> {
+        let chunks = sqlx::query_as(
+            "SELECT l.hash,
+                array(
+                    SELECT shard_id FROM chunk_placement WHERE chunk_hash = l.hash ORDER BY shard_id
+                ) AS shard_ids,
+                array(
+                    SELECT stored_hash FROM chunk_placement WHERE chunk_hash = l.hash
+                    ORDER BY shard_id
+                ) AS stored_hashes
+             FROM chunk_lookup l
+             WHERE l.refs > 0 AND l.hash > $1
+             ORDER BY l.hash
+             LIMIT $2",
+        )
+        .bind(
+            after
+                .as_ref()
+                .map_or(&[][..], |hash| hash.as_bytes().as_slice()),
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chunks)
+    }
+
+    /// Moves the placement of the chunk `hash` from the shard `from` to the shard `to`, which
+    /// must already store what the placement holds.
+    ///
+    /// Returns [`Release::Kept`] (moving nothing) if the chunk is unreferenced, or is no longer
+    /// placed on `from`.
+    pub async fn move_placement(&self, hash: Hash, from: Uuid, to: Uuid) -> Result<Release> {
+        let mut txn = self.pool.begin().await?;
+
+        // Unreferenced chunks are left to garbage collection, which locks them the same way.
+        let locked =
+            sqlx::query("SELECT 1 FROM chunk_lookup WHERE hash = $1 AND refs > 0 FOR UPDATE")
+                .bind(hash.as_bytes().as_slice())
+                .fetch_optional(&mut *txn)
+                .await?;
+
+        if locked.is_none() {
+            return Ok(Release::Kept);
+        }
+
+        let placement: Option<(Option<i16>, Vec<u8>)> = sqlx::query_as(
+            "SELECT piece, stored_hash FROM chunk_placement
+             WHERE shard_id = $1 AND chunk_hash = $2",
+        )
+        .bind(from)
+        .bind(hash.as_bytes().as_slice())
+        .fetch_optional(&mut *txn)
+        .await?;
+
+        let Some((piece, stored_hash)) = placement else {
+            return Ok(Release::Kept);
+        };
+
+        let placement = Placement {
+            shard_id: to,
+            piece: piece.map(usize::try_from).transpose()?,
+            stored_hash: Hash::try_from(stored_hash)?,
+        };
+
+        let release = delete_placement(&mut txn, hash, from).await?;
+        insert_placement(&mut txn, hash, &placement).await?;
+        txn.commit().await?;
+
+        Ok(release)
+    }
+
+    /// Records that `stored_hash` is about to be stored on `shard_id`, so that it isn't deleted
+    /// there until a placement is recorded for it. Returns `false` (recording nothing) if it is
+    /// being deleted there, in which case it must not be stored until that is finished.
+    pub async fn begin_store(&self, shard_id: Uuid, stored_hash: Hash) -> Result<bool> {
+        let begun = sqlx::query(
+            "INSERT INTO pending_writes (shard_id, stored_hash, deleting, updated)
+             VALUES ($1, $2, FALSE, now())
+             ON CONFLICT (shard_id, stored_hash) DO UPDATE SET updated = now()
+             WHERE NOT pending_writes.deleting",
+        )
+        .bind(shard_id)
+        .bind(stored_hash.as_bytes().as_slice())
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(begun > 0)
+    }
+
+    /// Forgets the recorded deletion of `stored_hash` from `shard_id`, once the shard has deleted
+    /// it.
+    pub async fn finish_delete(&self, shard_id: Uuid, stored_hash: Hash) -> Result<()> {
+        sqlx::query(
+            "DELETE FROM pending_writes WHERE shard_id = $1 AND stored_hash = $2 AND deleting",
+        )
+        .bind(shard_id)
+        .bind(stored_hash.as_bytes().as_slice())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns up to `limit` stores that have gone unrecorded for longer than `grace`, and
+    /// deletions that have been left unfinished for longer than `timeout`.
+    pub async fn get_stale_writes(
+        &self,
+        grace: Duration,
+        timeout: Duration,
+        limit: i64,
+    ) -> Result<Vec<PendingWrite>> {
+        let writes = sqlx::query_as(
+            "SELECT shard_id, stored_hash, deleting FROM pending_writes
+             WHERE updated < now() - make_interval(secs => CASE WHEN deleting THEN $2 ELSE $1 END)
+             ORDER BY updated
+             LIMIT $3",
+        )
+        .bind(grace.as_secs_f64())
+        .bind(timeout.as_secs_f64())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(writes)
+    }
+
+    /// Gives up on a store of `stored_hash` on `shard_id` that has gone unrecorded for longer than
+    /// `grace`. Returns [`Release::Delete`] if the shard should delete what it stored, or
+    /// [`Release::Kept`] if the store was recorded or begun again meanwhile.
+    pub async fn abandon_store(
+        &self,
+        shard_id: Uuid,
+        stored_hash: Hash,
+        grace: Duration,
+    ) -> Result<Release> {
+        let mut txn = self.pool.begin().await?;
+
+        let abandoned = sqlx::query(
+            "DELETE FROM pending_writes
+             WHERE shard_id = $1 AND stored_hash = $2 AND NOT deleting
+                AND updated < now() - make_interval(secs => $3)",
+        )
+        .bind(shard_id)
+        .bind(stored_hash.as_bytes().as_slice())
+        .bind(grace.as_secs_f64())
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+
+        if abandoned == 0 {
+            return Ok(Release::Kept);
+        }
+
+        let release = match begin_delete(&mut txn, shard_id, stored_hash).await? {
+            Release::Shared => Release::Kept,
+            release => release,
+        };
+        txn.commit().await?;
+
+        Ok(release)
+    }
+
+    /// Returns up to `limit` referenced chunks with fewer replicas or pieces placed on shards
+    /// other than `lost` than they should have, those closest to being lost first. Chunks in
+    /// `skip` are left out, as are chunks with nothing placed on any other shard.
+    pub async fn get_under_replicated_chunks(
+        &self,
+        lost: &[Uuid],
+        skip: &[Hash],
+        limit: i64,
+    ) -> Result<Vec<UnderReplicatedChunk>> {
+        let chunks = sqlx::query_as(
+            "SELECT l.hash, 0 AS offset, l.length, l.length AS chunk_length,
+                l.data_pieces, l.parity_pieces, l.replicas,
+                coalesce(array_agg(p.shard_id) FILTER (WHERE p.piece IS NULL), '{}') AS shard_ids,
+                coalesce(array_agg(p.shard_id ORDER BY p.piece, p.shard_id)
+                    FILTER (WHERE p.piece IS NOT NULL), '{}') AS piece_shard_ids,
+                coalesce(array_agg(p.piece ORDER BY p.piece, p.shard_id)
+                    FILTER (WHERE p.piece IS NOT NULL), '{}') AS pieces,
+                coalesce(array_agg(p.stored_hash ORDER BY p.piece, p.shard_id)
+                    FILTER (WHERE p.piece IS NOT NULL), '{}') AS piece_hashes
+             FROM chunk_lookup l
+             JOIN chunk_placement p ON p.chunk_hash = l.hash AND p.shard_id <> ALL($1)
+             WHERE l.refs > 0 AND l.hash <> ALL($2)
+             GROUP BY l.hash
+             HAVING count(DISTINCT p.piece) FILTER (WHERE l.data_pieces IS NOT NULL)
+                + count(*) FILTER (WHERE l.data_pieces IS NULL)
+                < coalesce(l.data_pieces + l.parity_pieces, l.replicas, 1)
+             ORDER BY count(DISTINCT p.piece) FILTER (WHERE l.data_pieces IS NOT NULL)
+                + count(*) FILTER (WHERE l.data_pieces IS NULL)
+                - coalesce(l.data_pieces, 1), l.hash
+             LIMIT $3",
+        )
+        .bind(lost)
+        .bind(
+            skip.iter()
+                .map(|hash| hash.as_bytes().to_vec())
+                .collect::<Vec<_>>(),
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chunks)
+    }
+
+    /// Records further placements of the chunk `hash`, whose shards must already store what they
+    /// hold. Returns `false` (recording nothing) if the chunk is unreferenced.
+    pub async fn add_placements(&self, hash: Hash, placements: &[Placement]) -> Result<bool> {
+        let mut txn = self.pool.begin().await?;
+
+        // Unreferenced chunks are left to garbage collection, which locks them the same way.
+        let locked =
+            sqlx::query("SELECT 1 FROM chunk_lookup WHERE hash = $1 AND refs > 0 FOR UPDATE")
+                .bind(hash.as_bytes().as_slice())
+                .fetch_optional(&mut *txn)
+                .await?;
+
+        if locked.is_none() {
+            return Ok(false);
+        }
+
+        for placement in placements {
+            insert_placement(&mut txn, hash, placement).await?;
+        }
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Deletes every placement on `shard_id` that is stored under `stored_hash`, e.g. because the
+    /// shard found its copy corrupt. Returns how many were deleted, and whether the shard's copy
+    /// should be deleted too, as [`Release::Delete`], or is being stored there again, as
+    /// [`Release::Shared`].
+    pub async fn drop_stored_placements(
+        &self,
+        shard_id: Uuid,
+        stored_hash: Hash,
+    ) -> Result<(u64, Release)> {
+        let mut txn = self.pool.begin().await?;
+
+        let dropped =
+            sqlx::query("DELETE FROM chunk_placement WHERE shard_id = $1 AND stored_hash = $2")
+                .bind(shard_id)
+                .bind(stored_hash.as_bytes().as_slice())
+                .execute(&mut *txn)
+                .await?
+                .rows_affected();
+
+        sqlx::query("UPDATE shards SET chunks = chunks - $2 WHERE id = $1")
+            .bind(shard_id)
+            .bind(i64::try_from(dropped)?)
+            .execute(&mut *txn)
+            .await?;
+
+        let release = begin_delete(&mut txn, shard_id, stored_hash).await?;
+        txn.commit().await?;
+
+        Ok((dropped, release))
+    }
+
+    /// Forgets the chunk `hash`, if it is unreferenced and no longer placed on any shard.
+    pub async fn remove_chunk(&self, hash: Hash) -> Result<bool> {
+        let removed = sqlx::query(
+            "DELETE FROM chunk_lookup l
+             WHERE l.hash = $1 AND l.refs = 0
+                AND NOT EXISTS (SELECT 1 FROM chunk_placement WHERE chunk_hash = l.hash)",
+        )
+        .bind(hash.as_bytes().as_slice())
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(removed > 0)
+    }
+
+    pub async fn add_upload_session(
+        &self,
+        id: Uuid,
+        name: &str,
+        content_type: &str,
+        size: u64,
+        replicas: Option<usize>,
+        policy: Option<Policy>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO upload_sessions
+                (id, name, content_type, size, upload_offset, tail, replicas, policy, created,
+                    updated)
+             VALUES ($1, $2, $3, $4, 0, '', $5, $6, now(), now())",
+        )
+        .bind(id)
+        .bind(name)
+        .bind(content_type)
+        .bind(i64::try_from(size)?)
+        .bind(replicas.map(i32::try_from).transpose()?)
+        .bind(policy.map(|policy| policy.as_str()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_upload_session(&self, id: Uuid) -> Result<Option<UploadSession>> {
+        let session = sqlx::query_as(
+            "SELECT id, name, content_type, size, upload_offset, tail, replicas, policy,
+                (SELECT count(*) FROM upload_session_chunks WHERE session = id) AS chunks
+             FROM upload_sessions WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Records progress on a resumable upload: the newly written chunks, which the session holds a
+    /// reference to, and the new offset and unstored tail.
+    ///
+    /// Returns `false` (recording nothing) if the session no longer exists, or its offset is no
+    /// longer `session.upload_offset`, i.e. another request advanced it concurrently.
+    pub async fn advance_upload_session(
+        &self,
+        session: &UploadSession,
+        upload_offset: u64,
+        tail: &[u8],
+        chunks: &[PlacedChunk],
+    ) -> Result<bool> {
+        let mut txn = self.pool.begin().await?;
+
+        let updated = sqlx::query(
+            "UPDATE upload_sessions SET upload_offset = $3, tail = $4, updated = now()
+             WHERE id = $1 AND upload_offset = $2",
+        )
+        .bind(session.id)
+        .bind(session.upload_offset)
+        .bind(i64::try_from(upload_offset)?)
+        .bind(tail)
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Ok(false);
+        }
+
+        reference_chunks(&mut txn, chunks).await?;
+
+        for (seq, chunk) in (session.chunks..).zip(chunks) {
+            sqlx::query(
+                "INSERT INTO upload_session_chunks (session, seq, chunk_hash) VALUES ($1, $2, $3)",
+            )
+            .bind(session.id)
+            .bind(seq)
+            .bind(chunk.hash.as_bytes().as_slice())
+            .execute(&mut *txn)
+            .await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Turns a resumable upload into a grouping with the same ID, given the chunks written since
+    /// the session was last advanced. The session's references to its chunks pass to the grouping.
+    ///
+    /// Returns `false` (recording nothing) under the same conditions as
+    /// [`Self::advance_upload_session`].
+    pub async fn finish_upload_session(
+        &self,
+        session: &UploadSession,
+        chunks: &[PlacedChunk],
+    ) -> Result<bool> {
+        let mut txn = self.pool.begin().await?;
+
+        let locked = sqlx::query(
+            "SELECT 1 FROM upload_sessions WHERE id = $1 AND upload_offset = $2 FOR UPDATE",
+        )
+        .bind(session.id)
+        .bind(session.upload_offset)
+        .fetch_optional(&mut *txn)
+        .await?;
+
+        if locked.is_none() {
+            return Ok(false);
+        }
+
+        insert_grouping(
+            &mut txn,
+            session.id,
+            &session.name,
+            &session.content_type,
+            u64::try_from(session.size)?,
+        )
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO grouping_chunks (grouping, seq, chunk_hash, chunk_offset, length)
+             SELECT s.session, s.seq, s.chunk_hash, 0, l.length
+             FROM upload_session_chunks s JOIN chunk_lookup l ON l.hash = s.chunk_hash
+             WHERE s.session = $1",
+        )
+        .bind(session.id)
+        .execute(&mut *txn)
+        .await?;
+
+        reference_chunks(&mut txn, chunks).await?;
+        insert_grouping_chunks(&mut txn, session.id, session.chunks, chunks).await?;
+
+        sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+            .bind(session.id)
+            .execute(&mut *txn)
+            .await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Records a newly stored pack chunk, which is unreferenced until media packed into it are
+    /// recorded.
+    pub async fn add_pack(&self, hash: Hash, length: usize, shard_ids: &[Uuid]) -> Result<()> {
+        let placements = shard_ids
+            .iter()
+            .map(|&shard_id| Placement::replica(shard_id, hash))
+            .collect::<Vec<_>>();
+
+        let mut txn = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO chunk_lookup (hash, length, refs, created, packed, unreferenced, replicas)
+             VALUES ($1, $2, 0, now(), TRUE, now(), $3)
+             ON CONFLICT (hash) DO NOTHING",
+        )
+        .bind(hash.as_bytes().as_slice())
+        .bind(i32::try_from(length)?)
+        .bind(i16::try_from(shard_ids.len())?)
+        .execute(&mut *txn)
+        .await?;
+
+        insert_placements(&mut txn, hash, None, &placements).await?;
+
+        txn.commit().await?;
 
         Ok(())
     }
+
+    /// Returns the packs with less than `live_percent` percent of their bytes still referenced.
+    pub async fn get_sparse_packs(&self, live_percent: u8) -> Result<Vec<StoredChunk>> {
+        let packs = sqlx::query_as(
+            "SELECT l.hash,
+                array(SELECT shard_id FROM chunk_placement WHERE chunk_hash = l.hash) AS shard_ids
+             FROM chunk_lookup l LEFT JOIN grouping_chunks c ON c.chunk_hash = l.hash
+             WHERE l.packed AND l.refs > 0
+             GROUP BY l.hash, l.length
+             HAVING coalesce(sum(c.length), 0) * 100 < l.length::BIGINT * $1",
+        )
+        .bind(i64::from(live_percent))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(packs)
+    }
+
+    /// Returns the grouping chunks stored in the pack `hash`.
+    pub async fn get_packed_objects(&self, hash: Hash) -> Result<Vec<PackedObject>> {
+        let objects = sqlx::query_as(
+            "SELECT grouping, seq, chunk_offset AS offset, length FROM grouping_chunks
+             WHERE chunk_hash = $1",
+        )
+        .bind(hash.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(objects)
+    }
+
+    /// Moves a grouping chunk from the pack `from` to the part of another chunk given by `to`.
+    ///
+    /// Returns `false` (recording nothing) if the grouping chunk is no longer in `from`.
+    pub async fn repack_object(
+        &self,
+        object: &PackedObject,
+        from: Hash,
+        to: &PlacedChunk,
+    ) -> Result<bool> {
+        let mut txn = self.pool.begin().await?;
+
+        // Keep the grouping from being deleted while its chunk moves.
+        sqlx::query("SELECT 1 FROM groupings WHERE id = $1 FOR SHARE")
+            .bind(object.grouping)
+            .execute(&mut *txn)
+            .await?;
+
+        let moved = sqlx::query(
+            "UPDATE grouping_chunks SET chunk_hash = $4, chunk_offset = $5, length = $6
+             WHERE grouping = $1 AND seq = $2 AND chunk_hash = $3",
+        )
+        .bind(object.grouping)
+        .bind(object.seq)
+        .bind(from.as_bytes().as_slice())
+        .bind(to.hash.as_bytes().as_slice())
+        .bind(i32::try_from(to.range.start)?)
+        .bind(i32::try_from(to.range.len())?)
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+
+        if moved == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            "UPDATE chunk_lookup
+             SET refs = refs - 1, unreferenced = CASE WHEN refs = 1 THEN now() END
+             WHERE hash = $1",
+        )
+        .bind(from.as_bytes().as_slice())
+        .execute(&mut *txn)
+        .await?;
+
+        reference_chunks(&mut txn, std::slice::from_ref(to)).await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Deletes a resumable upload, releasing its references to its chunks. Returns `false` if it
+    /// did not exist.
+    pub async fn delete_upload_session(&self, id: Uuid) -> Result<bool> {
+        let mut txn = self.pool.begin().await?;
+
+        let locked = sqlx::query("SELECT 1 FROM upload_sessions WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *txn)
+            .await?;
+
+        if locked.is_none() {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            "UPDATE chunk_lookup l
+             SET refs = l.refs - s.refs, unreferenced = CASE WHEN l.refs = s.refs THEN now() END
+             FROM (
+                SELECT chunk_hash, count(*) AS refs FROM upload_session_chunks
+                WHERE session = $1 GROUP BY chunk_hash
+             ) s
+             WHERE l.hash = s.chunk_hash",
+        )
+        .bind(id)
+        .execute(&mut *txn)
+        .await?;
+
+        let deleted = sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+            .bind(id)
+            .execute(&mut *txn)
+            .await?
+            .rows_affected();
+
+        txn.commit().await?;
+
+        Ok(deleted > 0)
+    }
+}
+
+async fn insert_grouping(
+    conn: &mut PgConnection,
+    id: Uuid,
+    name: &str,
+    content_type: &str,
+    size: u64,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO groupings (id, name, content_type, size, created)
+         VALUES ($1, $2, $3, $4, now())",
+    )
+    .bind(id)
+    .bind(name)
+    .bind(content_type)
+    .bind(i64::try_from(size)?)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Records `chunks` as the chunks of a grouping, starting at sequence number `first_seq`.
+async fn insert_grouping_chunks(
+    conn: &mut PgConnection,
+    grouping: Uuid,
+    first_seq: i64,
+    chunks: &[PlacedChunk],
+) -> Result<()> {
+    for (seq, chunk) in (first_seq..).zip(chunks) {
+        sqlx::query(
+            "INSERT INTO grouping_chunks (grouping, seq, chunk_hash, chunk_offset, length)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(grouping)
+        .bind(seq)
+        .bind(chunk.hash.as_bytes().as_slice())
+        .bind(i32::try_from(chunk.range.start)?)
+        .bind(i32::try_from(chunk.range.len())?)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Adds a reference to each of `chunks`, recording the chunks that were newly stored and where.
+async fn reference_chunks(conn: &mut PgConnection, chunks: &[PlacedChunk]) -> Result<()> {
+    for chunk in chunks {
+        sqlx::query(
+            "INSERT INTO chunk_lookup
+                (hash, length, refs, created, data_pieces, parity_pieces, replicas)
+             VALUES ($1, $2, 1, now(), $3, $4, $5)
+             ON CONFLICT (hash) DO UPDATE SET refs = chunk_lookup.refs + 1, unreferenced = NULL,
+                replicas = greatest(chunk_lookup.replicas, excluded.replicas)",
+        )
+        .bind(chunk.hash.as_bytes().as_slice())
+        .bind(i32::try_from(chunk.length)?)
+        .bind(
+            chunk
+                .coding
+                .map(|coding| i16::try_from(coding.data))
+                .transpose()?,
+        )
+        .bind(
+            chunk
+                .coding
+                .map(|coding| i16::try_from(coding.parity))
+                .transpose()?,
+        )
+        .bind(
+            chunk
+                .coding
+                .is_none()
+                .then(|| i16::try_from(chunk.replicas))
+                .transpose()?,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        insert_placements(conn, chunk.hash, chunk.coding, &chunk.placements).await?;
+    }
+
+    Ok(())
+}
+
+/// Records that the chunk `hash`, coded with `coding`, is stored at `placements`.
+async fn insert_placements(
+    conn: &mut PgConnection,
+    hash: Hash,
+    coding: Option<ErasureCoding>,
+    placements: &[Placement],
+) -> Result<()> {
+    let (data_pieces, parity_pieces): (Option<i16>, Option<i16>) =
+        sqlx::query_as("SELECT data_pieces, parity_pieces FROM chunk_lookup WHERE hash = $1")
+            .bind(hash.as_bytes().as_slice())
+            .fetch_one(&mut *conn)
+            .await?;
+
+    // A concurrent upload of the same new chunk may have recorded it first, coded differently;
+    // what this one stored can't be read as that coding, so it is left unrecorded, for garbage
+    // collection to delete from its shards once its stores are stale.
+    if ErasureCoding::from_columns(data_pieces, parity_pieces)? != coding {
+        warn!("Chunk {hash} was recorded with another coding; not recording where it was stored");
+
+        return Ok(());
+    }
+
+    for placement in placements {
+        insert_placement(conn, hash, placement).await?;
+    }
+
+    Ok(())
+}
+
+/// Records that the chunk `hash` is stored at `placement`.
+async fn insert_placement(
+    conn: &mut PgConnection,
+    hash: Hash,
+    placement: &Placement,
+) -> Result<()> {
+    // Concurrent uploads of the same new chunk may both have stored it on the same shard.
+    let placed = sqlx::query(
+        "INSERT INTO chunk_placement (shard_id, chunk_hash, piece, stored_hash)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(placement.shard_id)
+    .bind(hash.as_bytes().as_slice())
+    .bind(placement.piece.map(i16::try_from).transpose()?)
+    .bind(placement.stored_hash.as_bytes().as_slice())
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    if placed > 0 {
+        sqlx::query("UPDATE shards SET chunks = chunks + 1 WHERE id = $1")
+            .bind(placement.shard_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    // The placement keeps what was stored from being deleted from now on. Other stores of the
+    // same hash on the shard stored the same bytes, so they are covered by it too.
+    sqlx::query(
+        "DELETE FROM pending_writes WHERE shard_id = $1 AND stored_hash = $2 AND NOT deleting",
+    )
+    .bind(placement.shard_id)
+    .bind(placement.stored_hash.as_bytes().as_slice())
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes the placement of the chunk `hash` on `shard_id`, and returns whether the shard still
+/// needs what the placement held.
+async fn delete_placement(conn: &mut PgConnection, hash: Hash, shard_id: Uuid) -> Result<Release> {
+    let deleted: Option<Vec<u8>> = sqlx::query_scalar(
+        "DELETE FROM chunk_placement WHERE shard_id = $1 AND chunk_hash = $2
+         RETURNING stored_hash",
+    )
+    .bind(shard_id)
+    .bind(hash.as_bytes().as_slice())
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some(stored_hash) = deleted else {
+        return Ok(Release::Kept);
+    };
+
+    sqlx::query("UPDATE shards SET chunks = chunks - 1 WHERE id = $1")
+        .bind(shard_id)
+        .execute(&mut *conn)
+        .await?;
+
+    begin_delete(conn, shard_id, Hash::try_from(stored_hash)?).await
+}
+
+/// Records that `stored_hash` is to be deleted from `shard_id`, unless a placement on the shard
+/// is still stored under it, or it is being stored there.
+async fn begin_delete(
+    conn: &mut PgConnection,
+    shard_id: Uuid,
+    stored_hash: Hash,
+) -> Result<Release> {
+    // Inserted before looking for placements, so that a store recorded concurrently is either
+    // still pending here, or already placed by the time they are looked for.
+    let begun = sqlx::query(
+        "INSERT INTO pending_writes (shard_id, stored_hash, deleting, updated)
+         VALUES ($1, $2, TRUE, now())
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(shard_id)
+    .bind(stored_hash.as_bytes().as_slice())
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    if begun == 0 {
+        return Ok(Release::Shared);
+    }
+
+    // Pieces of different chunks may be identical, e.g. when they are all padding.
+    let shared = sqlx::query(
+        "SELECT 1 FROM chunk_placement WHERE shard_id = $1 AND stored_hash = $2 LIMIT 1",
+    )
+    .bind(shard_id)
+    .bind(stored_hash.as_bytes().as_slice())
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if shared.is_some() {
+        sqlx::query(
+            "DELETE FROM pending_writes WHERE shard_id = $1 AND stored_hash = $2 AND deleting",
+        )
+        .bind(shard_id)
+        .bind(stored_hash.as_bytes().as_slice())
+        .execute(&mut *conn)
+        .await?;
+
+        return Ok(Release::Shared);
+    }
+
+    Ok(Release::Delete(stored_hash))
 }
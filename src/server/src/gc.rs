@@ -0,0 +1,189 @@
+//! Garbage collection of chunks that are no longer referenced by any media.
+//!
+//! A chunk is only collected once it has been unreferenced for the grace period, which leaves
+//! time for in-flight uploads that found it unreferenced to store and reference it again. Each
+//! placement is removed from the database before the chunk is deleted from its shard, so the chunk
+//! is never read from a shard it is being deleted from; chunks on shards that are offline are
+//! collected once they reconnect.
+//!
+//! Pieces of different erasure coded chunks may be identical, so what a shard stores for a
+//! placement is only deleted once no other placement on the shard is stored under the same hash.
+//! Nor is it deleted while an upload is storing the same hash on the shard again, and uploads wait
+//! for such deletions to finish before storing, so a deletion can never undo a newer store.
+//!
+//! Every store is recorded before it is made, so what was stored but never placed, e.g. by an
+//! upload that failed partway, is deleted too, once it has gone unrecorded for the grace period.
+//! Deletions that failed are retried.
+
+use crate::{
+    cfg,
+    db_store::{PendingWrite, Release, StoredChunk},
+    net::shards::{self, Peer},
+};
+use anyhow::Result;
+use lib::Hash;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Maximum number of chunks collected per pass.
+const BATCH_LEN: i64 = 1024;
+
+/// Starts collecting unreferenced chunks periodically.
+pub fn start() {
+    tokio::spawn(collect_chunks());
+}
+
+async fn collect_chunks() {
+    let gc = &cfg::get().gc;
+    let grace = Duration::from_millis(gc.grace);
+
+    let mut interval = interval(Duration::from_millis(gc.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let db_store = crate::DB_STORE.read().await;
+        let chunks = db_store
+            .get()
+            .unwrap()
+            .get_collectable_chunks(grace, BATCH_LEN)
+            .await;
+        drop(db_store);
+
+        let chunks = match chunks {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                error!("Error finding chunks to collect: {err:?}");
+                continue;
+            }
+        };
+
+        let mut collected = 0;
+        for chunk in &chunks {
+            match collect(chunk, grace).await {
+                Ok(true) => collected += 1,
+                Ok(false) => {}
+                Err(err) => error!("Error collecting chunk {}: {err:?}", chunk.hash),
+            }
+        }
+
+        if !chunks.is_empty() {
+            debug!(
+                "Collected {collected} of {} unreferenced chunks",
+                chunks.len()
+            );
+        }
+
+        sweep_writes(grace).await;
+    }
+}
+
+/// Deletes what was stored on shards but has gone unrecorded for the grace period, and retries
+/// deletions that didn't finish.
+async fn sweep_writes(grace: Duration) {
+    let timeout = Duration::from_millis(cfg::get().timeout.message);
+
+    let db_store = crate::DB_STORE.read().await;
+    let writes = db_store
+        .get()
+        .unwrap()
+        .get_stale_writes(grace, timeout, BATCH_LEN)
+        .await;
+    drop(db_store);
+
+    let writes = match writes {
+        Ok(writes) => writes,
+        Err(err) => {
+            error!("Error finding stale writes: {err:?}");
+            return;
+        }
+    };
+
+    for write in &writes {
+        if let Err(err) = sweep(write, grace).await {
+            error!(
+                "Error sweeping {} on shard {}: {err:?}",
+                write.stored_hash, write.shard_id
+            );
+        }
+    }
+
+    if !writes.is_empty() {
+        debug!("Swept {} stale writes", writes.len());
+    }
+}
+
+async fn sweep(write: &PendingWrite, grace: Duration) -> Result<()> {
+    let Some(shard) = shards::get(write.shard_id).await else {
+        return Ok(());
+    };
+
+    if !write.deleting {
+        let db_store = crate::DB_STORE.read().await;
+        let release = db_store
+            .get()
+            .unwrap()
+            .abandon_store(write.shard_id, write.stored_hash, grace)
+            .await?;
+        drop(db_store);
+
+        if release != Release::Delete(write.stored_hash) {
+            return Ok(());
+        }
+    }
+
+    delete(&shard, write.stored_hash).await
+}
+
+/// Deletes what `shard` stores under `stored_hash`, whose deletion must have been recorded (see
+/// [`Release::Delete`]), and forgets the deletion once it is done. A deletion that fails is left
+/// recorded, to be retried.
+pub async fn delete(shard: &Peer, stored_hash: Hash) -> Result<()> {
+    shard.delete(stored_hash).await?;
+
+    let db_store = crate::DB_STORE.read().await;
+
+    db_store
+        .get()
+        .unwrap()
+        .finish_delete(shard.id(), stored_hash)
+        .await
+}
+
+/// Deletes `chunk` from every connected shard it is placed on, and forgets it once it is placed
+/// on none. Returns whether it was forgotten.
+async fn collect(chunk: &StoredChunk, grace: Duration) -> Result<bool> {
+    for &shard_id in &chunk.shard_ids {
+        let Some(shard) = shards::get(shard_id).await else {
+            continue;
+        };
+
+        let db_store = crate::DB_STORE.read().await;
+        let release = db_store
+            .get()
+            .unwrap()
+            .release_placement(chunk.hash, shard_id, grace)
+            .await?;
+        drop(db_store);
+
+        let stored_hash = match release {
+            Release::Delete(stored_hash) => stored_hash,
+            Release::Shared => continue,
+
+            // The chunk was referenced again, or is being collected concurrently.
+            Release::Kept => return Ok(false),
+        };
+
+        if let Err(err) = delete(&shard, stored_hash).await {
+            warn!(
+                "Error deleting chunk {} from shard {shard_id}, to be retried: {err:?}",
+                chunk.hash
+            );
+        }
+    }
+
+    let db_store = crate::DB_STORE.read().await;
+
+    db_store.get().unwrap().remove_chunk(chunk.hash).await
+}
@@ -7,7 +7,13 @@ extern crate sqlx;
 
 mod cfg;
 mod db_store;
+mod gc;
 mod net;
+mod packing;
+mod placement;
+mod rebalance;
+mod redundancy;
+mod repair;
 
 use anyhow::Result;
 use once_cell::sync::OnceCell;
@@ -21,6 +27,7 @@ use tracing::{Instrument, Level};
 use uuid::Uuid;
 
 static PEER_CTOKENS: Mutex<BTreeMap<Uuid, CancellationToken>> = Mutex::const_new(BTreeMap::new());
+static PEERS: Mutex<BTreeMap<Uuid, net::shards::Peer>> = Mutex::const_new(BTreeMap::new());
 static DB_STORE: RwLock<OnceCell<db_store::DbStore>> = RwLock::const_new(OnceCell::new());
 
 fn agent() -> String {
@@ -43,6 +50,23 @@ async fn main() -> impl Termination {
 
 async fn start() -> Result<()> {
     connect_db().await?;
+
+    // No shard is connected yet, whatever was last recorded.
+    DB_STORE
+        .read()
+        .await
+        .get()
+        .unwrap()
+        .mark_all_offline()
+        .await?;
+
+    let key = net::shards::init_keypair().await?;
+    info!("Shards must expect the server key {key}");
+
+    packing::start();
+    gc::start();
+    rebalance::start();
+    repair::start();
     listen().await?;
 
     Ok(())
@@ -0,0 +1,179 @@
+use crate::{
+    cfg,
+    net::{api, shards},
+    rebalance,
+};
+use axum::{
+    extract::{Path, Request},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, put},
+    Router,
+};
+use lib::Hash;
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// What a shard needs to enroll.
+#[derive(Debug, Serialize)]
+struct Enrollment {
+    /// The key the shard must expect the server to present.
+    server_key: String,
+
+    /// A one-time token the shard may enroll with, if one was asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/admin/rebalance", get(progress).post(start))
+        .route("/admin/shards", get(shards))
+        .route("/admin/shards/{id}/drain", put(drain).delete(undrain))
+        .route(
+            "/admin/enrollment",
+            get(enrollment).post(add_enrollment_token),
+        )
+        .route_layer(middleware::from_fn(authorize))
+}
+
+/// Lets a request through only if it carries the configured admin token as a bearer token.
+async fn authorize(request: Request, next: Next) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    // Tokens are compared by hash, so how long that takes says nothing about the admin token.
+    let authorized = match (token, &cfg::get().admin.token) {
+        (Some(token), Some(admin)) => Hash::of(token.as_bytes()) == Hash::of(admin.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        return api::response::default()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    next.run(request).await
+}
+
+async fn progress() -> (StatusCode, Response) {
+    let progress = rebalance::progress().await;
+
+    (StatusCode::OK, api::response::json(&progress).unwrap())
+}
+
+/// Starts a rebalancing pass, unless one is already running.
+async fn start() -> (StatusCode, Response) {
+    rebalance::wake();
+
+    (StatusCode::ACCEPTED, api::response::empty().unwrap())
+}
+
+/// Lists every recorded shard, with its state and when it was last seen.
+async fn shards() -> (StatusCode, Response) {
+    let db_store = crate::DB_STORE.read().await;
+    let result = db_store.get().unwrap().get_shard_statuses().await;
+    drop(db_store);
+
+    match result {
+        Ok(shards) => (StatusCode::OK, api::response::json(&shards).unwrap()),
+
+        Err(err) => {
+            error!("Error listing shards: {err:?}");
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                api::response::empty().unwrap(),
+            )
+        }
+    }
+}
+
+async fn enrollment() -> (StatusCode, Response) {
+    let enrollment = Enrollment {
+        server_key: shards::public_key().to_string(),
+        token: None,
+    };
+
+    (StatusCode::OK, api::response::json(&enrollment).unwrap())
+}
+
+/// Creates a one-time token that a new shard can enroll with.
+async fn add_enrollment_token() -> (StatusCode, Response) {
+    let token = lib::hex::encode(&rand::random::<[u8; 32]>());
+    let ttl = Duration::from_millis(cfg::get().enrollment.ttl);
+
+    let db_store = crate::DB_STORE.read().await;
+    let result = db_store
+        .get()
+        .unwrap()
+        .add_enrollment_token(Hash::of(token.as_bytes()), ttl)
+        .await;
+    drop(db_store);
+
+    match result {
+        Ok(()) => {
+            let enrollment = Enrollment {
+                server_key: shards::public_key().to_string(),
+                token: Some(token),
+            };
+
+            (
+                StatusCode::CREATED,
+                api::response::json(&enrollment).unwrap(),
+            )
+        }
+
+        Err(err) => {
+            error!("Error adding enrollment token: {err:?}");
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                api::response::empty().unwrap(),
+            )
+        }
+    }
+}
+
+/// Stops placing chunks on a shard, and starts moving its chunks elsewhere.
+async fn drain(id: Path<Uuid>) -> (StatusCode, Response) {
+    set_draining(*id, true).await
+}
+
+/// Lets chunks be placed on a drained shard again.
+async fn undrain(id: Path<Uuid>) -> (StatusCode, Response) {
+    set_draining(*id, false).await
+}
+
+async fn set_draining(id: Uuid, draining: bool) -> (StatusCode, Response) {
+    let db_store = crate::DB_STORE.read().await;
+    let result = db_store.get().unwrap().set_draining(id, draining).await;
+    drop(db_store);
+
+    match result {
+        Ok(true) => {
+            debug!("Set shard {id} draining: {draining}");
+            rebalance::wake();
+
+            (StatusCode::NO_CONTENT, api::response::empty().unwrap())
+        }
+
+        Ok(false) => (StatusCode::NOT_FOUND, api::response::empty().unwrap()),
+
+        Err(err) => {
+            error!("Error setting shard {id} draining: {err:?}");
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                api::response::empty().unwrap(),
+            )
+        }
+    }
+}
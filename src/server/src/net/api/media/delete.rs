@@ -0,0 +1,34 @@
+use crate::net::api;
+use axum::{extract::Path, http::StatusCode, response::Response, routing::delete, Router};
+use uuid::Uuid;
+
+pub fn routes() -> Router {
+    Router::new().route("/{id}", delete(delete_media))
+}
+
+/// Deletes a grouping. Its chunks are reclaimed by garbage collection once no other media
+/// reference them.
+async fn delete_media(id: Path<Uuid>) -> (StatusCode, Response) {
+    let db_store = crate::DB_STORE.read().await;
+    let result = db_store.get().unwrap().delete_grouping(*id).await;
+    drop(db_store);
+
+    match result {
+        Ok(true) => {
+            debug!("Deleted media {}", *id);
+
+            (StatusCode::NO_CONTENT, api::response::empty().unwrap())
+        }
+
+        Ok(false) => (StatusCode::NOT_FOUND, api::response::empty().unwrap()),
+
+        Err(err) => {
+            error!("Error deleting media {}: {err:?}", *id);
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                api::response::empty().unwrap(),
+            )
+        }
+    }
+}
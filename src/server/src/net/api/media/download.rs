@@ -0,0 +1,269 @@
+use crate::{
+    db_store::{ChunkLocation, Grouping},
+    net::api::{
+        self,
+        range::{self, Ranges},
+    },
+    redundancy,
+};
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use futures::{stream, Stream, StreamExt};
+use std::ops::Range;
+use uuid::Uuid;
+
+/// Number of chunks fetched ahead of the one currently being sent.
+const PREFETCH_CHUNKS: usize = 4;
+
+pub fn routes() -> Router {
+    Router::new().route("/{id}", get(download))
+}
+
+async fn download(id: Path<Uuid>, headers: HeaderMap) -> (StatusCode, Response) {
+    let db_store = crate::DB_STORE.read().await;
+    let db_store = db_store.get().unwrap();
+
+    let grouping = match db_store.get_grouping(*id).await {
+        Ok(Some(grouping)) => grouping,
+        Ok(None) => return (StatusCode::NOT_FOUND, api::response::empty().unwrap()),
+
+        Err(err) => {
+            error!("Error reading grouping {}: {err:?}", *id);
+
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                api::response::empty().unwrap(),
+            );
+        }
+    };
+
+    let chunks = match db_store.get_grouping_chunks(*id).await {
+        Ok(chunks) => chunks,
+
+        Err(err) => {
+            error!("Error reading chunks of grouping {}: {err:?}", *id);
+
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                api::response::empty().unwrap(),
+            );
+        }
+    };
+
+    respond(&grouping, &chunks, &headers)
+}
+
+/// Builds the response to a request with the headers `headers` for the media `grouping`, made up
+/// of `chunks`.
+fn respond(
+    grouping: &Grouping,
+    chunks: &[ChunkLocation],
+    headers: &HeaderMap,
+) -> (StatusCode, Response) {
+    let size = grouping.size.unsigned_abs();
+    match range::parse(headers.get(header::RANGE), size) {
+        Ranges::Full => {
+            let response = api::response::default()
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::CONTENT_TYPE, content_type(grouping))
+                .header(header::CONTENT_LENGTH, size)
+                .body(Body::from_stream(range_stream(chunks, 0..size)))
+                .unwrap();
+
+            (StatusCode::OK, response)
+        }
+
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0].clone();
+            let response = api::response::default()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::CONTENT_TYPE, content_type(grouping))
+                .header(header::CONTENT_RANGE, content_range(&range, size))
+                .header(header::CONTENT_LENGTH, range.end - range.start)
+                .body(Body::from_stream(range_stream(chunks, range)))
+                .unwrap();
+
+            (StatusCode::PARTIAL_CONTENT, response)
+        }
+
+        Ranges::Partial(ranges) => (
+            StatusCode::PARTIAL_CONTENT,
+            multipart_response(grouping, chunks, ranges),
+        ),
+
+        Ranges::Unsatisfiable => {
+            let response = api::response::default()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Body::empty())
+                .unwrap();
+
+            (StatusCode::RANGE_NOT_SATISFIABLE, response)
+        }
+    }
+}
+
+/// The `Content-Type` to send `grouping` with. Content types are checked when media is uploaded,
+/// but media stored before that may have one that can't be sent, which is replaced.
+fn content_type(grouping: &Grouping) -> HeaderValue {
+    HeaderValue::from_str(&grouping.content_type).unwrap_or_else(|_| {
+        warn!(
+            "Stored content type {:?} is malformed",
+            grouping.content_type
+        );
+
+        HeaderValue::from_static("application/octet-stream")
+    })
+}
+
+fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{size}", range.start, range.end - 1)
+}
+
+/// Builds a `multipart/byteranges` response with one part per range.
+fn multipart_response(
+    grouping: &Grouping,
+    chunks: &[ChunkLocation],
+    ranges: Vec<Range<u64>>,
+) -> Response {
+    let size = grouping.size.unsigned_abs();
+    let boundary = Uuid::now_v7().simple().to_string();
+    let content_type = content_type(grouping);
+
+    let mut content_len = 0;
+    let mut parts = Vec::with_capacity(ranges.len() + 1);
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{boundary}\r\n{}: {}\r\n{}: {}\r\n\r\n",
+            header::CONTENT_TYPE,
+            content_type.to_str().unwrap(),
+            header::CONTENT_RANGE,
+            content_range(&range, size)
+        );
+
+        content_len += part_header.len() as u64 + (range.end - range.start);
+        parts.push(stream::once(async { Ok(Bytes::from(part_header)) }).boxed());
+        parts.push(range_stream(chunks, range).boxed());
+    }
+
+    let closing = format!("\r\n--{boundary}--\r\n");
+    content_len += closing.len() as u64;
+    parts.push(stream::once(async { Ok(Bytes::from(closing)) }).boxed());
+
+    api::response::default()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+        )
+        .header(header::CONTENT_LENGTH, content_len)
+        .body(Body::from_stream(stream::iter(parts).flatten()))
+        .unwrap()
+}
+
+/// Streams the media bytes in `range`, fetching only the chunks that overlap it.
+///
+/// Only [`PREFETCH_CHUNKS`] chunks are held in memory at a time.
+fn range_stream(
+    chunks: &[ChunkLocation],
+    range: Range<u64>,
+) -> impl Stream<Item = Result<Bytes>> + Send + 'static {
+    // Pair each overlapping chunk with the range of its bytes to send.
+    let mut chunk_start = 0;
+    let mut overlapping = Vec::new();
+    for location in chunks {
+        let chunk_end = chunk_start + location.length.unsigned_abs() as u64;
+
+        if chunk_start < range.end && range.start < chunk_end {
+            let start = range.start.saturating_sub(chunk_start);
+            let end = std::cmp::min(range.end, chunk_end) - chunk_start;
+
+            overlapping.push((location.clone(), (start as usize)..(end as usize)));
+        }
+
+        chunk_start = chunk_end;
+    }
+
+    stream::iter(overlapping)
+        .map(|(location, bytes)| async move {
+            let chunk = redundancy::fetch(&location).await?;
+            let offset = location.offset.unsigned_abs() as usize;
+            let bytes = (offset + bytes.start)..(offset + bytes.end);
+            if bytes.end > chunk.len() {
+                bail!("chunk {} is shorter than recorded", location.hash);
+            }
+
+            Ok(chunk.slice(bytes))
+        })
+        .buffered(PREFETCH_CHUNKS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grouping(content_type: &str, size: i64) -> Grouping {
+        Grouping {
+            content_type: content_type.to_string(),
+            size,
+        }
+    }
+
+    fn range(ranges: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(ranges).unwrap());
+
+        headers
+    }
+
+    #[test]
+    fn sends_stored_content_type() {
+        let (status, response) = respond(&grouping("image/png", 0), &[], &HeaderMap::new());
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn replaces_malformed_content_type() {
+        let grouping = grouping("text/plain\r\nX-Injected: 1", 100);
+
+        let (status, response) = respond(&grouping, &[], &HeaderMap::new());
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+
+        let (status, response) = respond(&grouping, &[], &range("bytes=0-9"));
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+
+        // The parts of a multipart response carry it in their own headers.
+        let (status, response) = respond(&grouping, &[], &range("bytes=0-9,20-29"));
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(
+            body.matches("content-type: application/octet-stream\r\n")
+                .count(),
+            2
+        );
+        assert!(!body.contains("X-Injected"));
+    }
+}
@@ -1,7 +1,12 @@
 use axum::Router;
 
+mod delete;
+mod download;
 mod upload;
 
 pub fn routes() -> Router {
-    Router::new().nest("/media", upload::routes())
+    Router::new()
+        .nest("/media", upload::routes())
+        .nest("/media", download::routes())
+        .nest("/media", delete::routes())
 }
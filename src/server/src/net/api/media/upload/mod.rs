@@ -1,4 +1,19 @@
-use axum::Router;
+use crate::{
+    db_store::{PlacedChunk, Placement},
+    net::shards,
+    packing,
+    placement::Cluster,
+    redundancy::{ErasureCoding, Policy, Redundancy},
+};
+use anyhow::Result;
+use axum::{
+    http::{HeaderValue, StatusCode},
+    Router,
+};
+use futures::future::try_join_all;
+use lib::{chunker::Chunker, Hash};
+use std::collections::HashMap;
+use uuid::Uuid;
 
 mod multipart;
 mod resumable;
@@ -8,3 +23,329 @@ pub fn routes() -> Router {
         .nest("/upload", multipart::routes())
         .nest("/upload", resumable::routes())
 }
+
+/// Splits a stream of media bytes into [`Chunk`](lib::chunk::Chunk)s, storing each as soon as it
+/// is cut.
+///
+/// Each chunk is stored with the given [`Redundancy`]. Chunks are identified by their hash, so a
+/// chunk that is already stored (by this or any other upload) is only referenced, rather than
+/// stored again, unless it is replicated on fewer shards than this upload asks for. Media no
+/// larger than the packing threshold are stored in a shared pack instead (see [`packing`]).
+///
+/// At most one chunk is buffered at a time, so memory use is independent of the media size.
+struct ChunkWriter {
+    cluster: Cluster,
+    redundancy: Redundancy,
+    chunker: Chunker,
+    seq: usize,
+    buffer: Vec<u8>,
+    size: u64,
+    placed: Vec<PlacedChunk>,
+    stored: HashMap<Hash, Option<ErasureCoding>>,
+}
+
+impl ChunkWriter {
+    fn new(cluster: Cluster, redundancy: Redundancy) -> Self {
+        Self::resume(cluster, redundancy, 0, &[])
+    }
+
+    /// Continues writing media that already has `seq` chunks stored, and whose uncut remainder
+    /// is `tail`.
+    fn resume(cluster: Cluster, redundancy: Redundancy, seq: usize, tail: &[u8]) -> Self {
+        let chunker = crate::cfg::get().chunking.chunker();
+
+        assert!(
+            redundancy.shards() > 0,
+            "chunks must be stored on at least one shard"
+        );
+        assert!(
+            cluster.domains() >= redundancy.shards(),
+            "not enough failure domains to store every replica or piece in a different one"
+        );
+        assert!(
+            tail.len() < chunker.max_len(),
+            "tail must be shorter than a chunk"
+        );
+
+        let mut buffer = Vec::with_capacity(chunker.max_len());
+        buffer.extend_from_slice(tail);
+
+        Self {
+            cluster,
+            redundancy,
+            chunker,
+            seq,
+            buffer,
+            size: 0,
+            placed: Vec::new(),
+            stored: HashMap::new(),
+        }
+    }
+
+    /// Number of media bytes written to this writer (excluding any resumed tail).
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        let max_len = self.chunker.max_len();
+
+        while !data.is_empty() {
+            let len = std::cmp::min(max_len - self.buffer.len(), data.len());
+            let (head, tail) = data.split_at(len);
+
+            self.buffer.extend_from_slice(head);
+            self.size += len as u64;
+            data = tail;
+
+            // Only cut once a whole chunk's worth of bytes is buffered, since later bytes may
+            // move the cut point.
+            if self.buffer.len() == max_len {
+                self.cut().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores the remaining buffered bytes, and returns every chunk that was written, in order.
+    async fn finish(mut self) -> Result<Vec<PlacedChunk>> {
+        if self.seq == 0 && packing::fits(self.buffer.len()) {
+            return Ok(vec![
+                packing::pack(self.buffer, self.redundancy.replicas()).await?,
+            ]);
+        }
+
+        while !self.buffer.is_empty() {
+            self.cut().await?;
+        }
+
+        Ok(self.placed)
+    }
+
+    /// Stops writing without storing the buffered bytes, returning the chunks that were written
+    /// and the buffered bytes, so writing can later be resumed.
+    fn suspend(self) -> (Vec<PlacedChunk>, Vec<u8>) {
+        (self.placed, self.buffer)
+    }
+
+    /// Cuts the first chunk from the buffered bytes, and stores it unless it is already stored.
+    async fn cut(&mut self) -> Result<()> {
+        let length = self.chunker.cut(&self.buffer);
+        let hash = Hash::of(&self.buffer[..length]);
+
+        let (coding, placements) = match self.store(hash, length).await {
+            Ok(stored) => stored,
+
+            Err(err) => {
+                // The buffered bytes follow the lost chunk, so they can't be stored either; drop
+                // them to keep what was stored contiguous.
+                self.buffer.clear();
+
+                return Err(err);
+            }
+        };
+
+        self.buffer.drain(..length);
+        self.placed.push(PlacedChunk {
+            hash,
+            length,
+            range: 0..length,
+            coding,
+            replicas: self.redundancy.replicas(),
+            placements,
+        });
+        self.seq += 1;
+
+        Ok(())
+    }
+
+    /// Stores the first `length` buffered bytes as the chunk `hash`, wherever needed to have it
+    /// stored as redundantly as this upload asks for, and returns its coding and where it was
+    /// newly stored.
+    async fn store(
+        &mut self,
+        hash: Hash,
+        length: usize,
+    ) -> Result<(Option<ErasureCoding>, Vec<Placement>)> {
+        if let Some(&coding) = self.stored.get(&hash) {
+            trace!("Chunk {hash} repeats within the upload");
+
+            return Ok((coding, Vec::new()));
+        }
+
+        let db_store = crate::DB_STORE.read().await;
+        let recorded = db_store.get().unwrap().get_recorded_chunk(hash).await?;
+        drop(db_store);
+
+        // Chunks that are unreferenced may be collected at any moment, so their placements can't
+        // be relied on; they are only used to keep the chunk's coding.
+        let (coding, stored_on) = match recorded {
+            Some(recorded) => (
+                ErasureCoding::from_columns(recorded.data_pieces, recorded.parity_pieces)?,
+                Some(recorded.shard_ids).filter(|_| recorded.refs > 0),
+            ),
+
+            None => (self.redundancy.coding(), None),
+        };
+
+        let placements = match coding {
+            // Missing pieces can't be stored without reading the chunk's others, so an erasure
+            // coded chunk that is already stored is left as it is.
+            Some(_) if stored_on.is_some() => {
+                trace!("Chunk {hash} is already stored");
+
+                return Ok((coding, Vec::new()));
+            }
+
+            Some(coding) => self.store_pieces(hash, length, coding).await?,
+            None => {
+                self.store_replicas(hash, length, &stored_on.unwrap_or_default())
+                    .await?
+            }
+        };
+
+        self.stored.insert(hash, coding);
+
+        Ok((coding, placements))
+    }
+
+    /// Stores the chunk `hash` on as many shards other than `stored_on` as needed to have it on
+    /// [`Redundancy::replicas`] shards.
+    async fn store_replicas(
+        &self,
+        hash: Hash,
+        length: usize,
+        stored_on: &[Uuid],
+    ) -> Result<Vec<Placement>> {
+        let replicas = self.redundancy.replicas();
+        let missing = replicas.saturating_sub(stored_on.len());
+        if missing == 0 {
+            trace!("Chunk {hash} is already stored");
+
+            return Ok(Vec::new());
+        }
+
+        let targets = self.cluster.place(hash, missing, stored_on);
+        if targets.len() < missing {
+            bail!(
+                "not enough failure domains have room to store chunk {hash} on {replicas} shards"
+            );
+        }
+
+        shards::store_all(&targets, hash, &self.buffer[..length]).await?;
+
+        let shard_ids = targets.iter().map(|shard| shard.id()).collect::<Vec<_>>();
+        trace!("Stored chunk {hash} ({length} bytes) on shards {shard_ids:?}");
+
+        Ok(shard_ids
+            .into_iter()
+            .map(|shard_id| Placement::replica(shard_id, hash))
+            .collect())
+    }
+
+    /// Encodes the chunk `hash` with `coding`, and stores each of its pieces on a different shard.
+    async fn store_pieces(
+        &self,
+        hash: Hash,
+        length: usize,
+        coding: ErasureCoding,
+    ) -> Result<Vec<Placement>> {
+        let targets = self.cluster.place(hash, coding.pieces(), &[]);
+        if targets.len() < coding.pieces() {
+            bail!(
+                "not enough failure domains have room to store the {} pieces of chunk {hash}",
+                coding.pieces()
+            );
+        }
+
+        let pieces = coding.encode(&self.buffer[..length])?;
+        let placements = targets
+            .iter()
+            .zip(&pieces)
+            .enumerate()
+            .map(|(piece, (shard, data))| Placement {
+                shard_id: shard.id(),
+                piece: Some(piece),
+                stored_hash: Hash::of(data),
+            })
+            .collect::<Vec<_>>();
+
+        try_join_all(targets.iter().zip(&pieces).zip(&placements).map(
+            |((shard, data), placement)| {
+                shards::store_all(std::slice::from_ref(shard), placement.stored_hash, data)
+            },
+        ))
+        .await?;
+
+        trace!(
+            "Stored chunk {hash} ({length} bytes) as {} pieces",
+            coding.pieces()
+        );
+
+        Ok(placements)
+    }
+}
+
+/// Resolves how to store each chunk of an upload, from the upload's overrides.
+///
+/// Fails with the status to respond with if the overrides are invalid, or there are too few
+/// shards to place chunks on.
+fn redundancy(
+    policy: Option<Policy>,
+    replicas: Option<usize>,
+    cluster: &Cluster,
+) -> Result<Redundancy, StatusCode> {
+    let redundancy = match Redundancy::resolve(policy, replicas) {
+        Ok(redundancy) => redundancy,
+        Err(err) => {
+            debug!("Rejecting upload: {err}");
+
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if cluster.domains() < redundancy.shards() {
+        warn!(
+            "Rejecting upload: {redundancy:?} requested, but only {} failure domains have room.",
+            cluster.domains()
+        );
+
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    Ok(redundancy)
+}
+
+/// Checks that `content_type` is a MIME type that can be sent back as a `Content-Type` header,
+/// and returns it as it should be stored, or `None` if it is malformed.
+fn content_type(content_type: &str) -> Option<String> {
+    let mime = content_type.parse::<mime::Mime>().ok()?;
+    HeaderValue::from_str(mime.as_ref()).ok()?;
+
+    Some(mime.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_types_are_checked() {
+        assert_eq!(content_type("image/png").as_deref(), Some("image/png"));
+        assert_eq!(
+            content_type("text/plain; charset=utf-8").as_deref(),
+            Some("text/plain; charset=utf-8")
+        );
+
+        for value in [
+            "",
+            "png",
+            "text/plain\n",
+            "text/plain\r\nX-Evil: 1",
+            "tëxt/plain",
+        ] {
+            assert_eq!(content_type(value), None, "{value:?}");
+        }
+    }
+}
@@ -1,9 +1,179 @@
-use axum::{extract::Multipart, http::StatusCode, response::Response, routing::post, Router};
+use super::ChunkWriter;
+use crate::{
+    net::api,
+    placement::Cluster,
+    redundancy::{Policy, Redundancy},
+};
+use anyhow::Result;
+use axum::{
+    extract::{
+        multipart::{Field, MultipartError},
+        DefaultBodyLimit, Multipart, Query,
+    },
+    http::StatusCode,
+    response::Response,
+    routing::post,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 pub fn routes() -> Router {
-    Router::new().route("/multipart", post(multipart))
+    Router::new()
+        .route("/multipart", post(multipart))
+        .layer(DefaultBodyLimit::disable())
 }
 
-async fn multipart(mut multipart: Multipart) -> (StatusCode, Response) {
-    todo!()
+#[derive(Debug, Deserialize)]
+struct UploadParams {
+    /// How to store each chunk redundantly, if not replicated.
+    policy: Option<Policy>,
+
+    /// Number of shards to store each chunk on, if not the cluster-wide replication factor.
+    replicas: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Uploaded {
+    id: Uuid,
+    name: String,
+    size: u64,
+}
+
+/// Stores every field of the request as a new grouping. Either all of them are stored, or none
+/// are: if any field fails, those stored before it are deleted again.
+async fn multipart(params: Query<UploadParams>, multipart: Multipart) -> (StatusCode, Response) {
+    let mut uploaded = Vec::new();
+
+    if let Err(status) = store_fields(&params, multipart, &mut uploaded).await {
+        roll_back(&uploaded).await;
+
+        return (status, api::response::empty().unwrap());
+    }
+
+    if uploaded.is_empty() {
+        return (StatusCode::BAD_REQUEST, api::response::empty().unwrap());
+    }
+
+    (StatusCode::CREATED, api::response::json(&uploaded).unwrap())
+}
+
+/// Stores each field in turn, adding it to `uploaded` once it is recorded. Returns the status to
+/// fail the request with if a field could not be stored.
+async fn store_fields(
+    params: &UploadParams,
+    mut multipart: Multipart,
+    uploaded: &mut Vec<Uploaded>,
+) -> Result<(), StatusCode> {
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => return Ok(()),
+
+            Err(err) => {
+                debug!("Malformed multipart request: {err:?}");
+
+                return Err(client_error(&err));
+            }
+        };
+
+        let Some(content_type) =
+            super::content_type(field.content_type().unwrap_or("application/octet-stream"))
+        else {
+            debug!("Malformed content type: {:?}", field.content_type());
+
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        let cluster = Cluster::snapshot().await.map_err(|err| {
+            error!("Error reading shard capacities: {err:?}");
+
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        let redundancy = super::redundancy(params.policy, params.replicas, &cluster)?;
+
+        let err = match store_field(field, content_type, cluster, redundancy).await {
+            Ok(media) => {
+                uploaded.push(media);
+                continue;
+            }
+            Err(err) => err,
+        };
+
+        // The client aborted or sent a malformed body partway through the field.
+        return match err.downcast::<MultipartError>() {
+            Ok(err) => {
+                debug!("Malformed multipart request: {err:?}");
+
+                Err(client_error(&err))
+            }
+
+            Err(err) => {
+                error!("Error storing media: {err:?}");
+
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+    }
+}
+
+/// The status for a request whose body could not be read. Any failure reading it is the client's,
+/// even one axum reports as a server error, such as the client aborting partway through.
+fn client_error(err: &MultipartError) -> StatusCode {
+    match err.status() {
+        status if status.is_server_error() => StatusCode::BAD_REQUEST,
+        status => status,
+    }
+}
+
+/// Deletes the groupings already stored by a request that failed. Their chunks are reclaimed by
+/// garbage collection once no other media reference them.
+async fn roll_back(uploaded: &[Uploaded]) {
+    let db_store = crate::DB_STORE.read().await;
+    let db_store = db_store.get().unwrap();
+
+    for media in uploaded {
+        match db_store.delete_grouping(media.id).await {
+            Ok(_) => debug!("Rolled back media {}", media.id),
+            Err(err) => error!("Error rolling back media {}: {err:?}", media.id),
+        }
+    }
+}
+
+/// Streams a single multipart field into chunks, and records it as a new grouping of the type
+/// `content_type`.
+async fn store_field(
+    mut field: Field<'_>,
+    content_type: String,
+    cluster: Cluster,
+    redundancy: Redundancy,
+) -> Result<Uploaded> {
+    let id = Uuid::now_v7();
+    let name = field
+        .file_name()
+        .or(field.name())
+        .unwrap_or_default()
+        .to_string();
+    debug!("Receiving media {id}: {name:?} ({content_type})");
+
+    let mut writer = ChunkWriter::new(cluster, redundancy);
+    while let Some(bytes) = field.chunk().await? {
+        writer.write(&bytes).await?;
+    }
+
+    let size = writer.size();
+    let chunks = writer.finish().await?;
+
+    let db_store = crate::DB_STORE.read().await;
+    db_store
+        .get()
+        .unwrap()
+        .add_grouping(id, &name, &content_type, size, &chunks)
+        .await?;
+    drop(db_store);
+
+    debug!("Stored media {id}: {size} bytes in {} chunks", chunks.len());
+
+    Ok(Uploaded { id, name, size })
 }
@@ -1,24 +1,409 @@
-use axum::{extract::Query, http::StatusCode, response::Response, routing::post, Json, Router};
-use serde::Deserialize;
+//! Resumable uploads, following the [tus 1.0](https://tus.io/protocols/resumable-upload) core
+//! protocol with the `creation` and `termination` extensions.
+//!
+//! Session state lives in the database, so an upload can continue across server restarts. Bytes
+//! that have not yet been cut into a chunk are kept with the session until more data arrives.
 
-pub fn routes() -> Router {
-    Router::new().route("/resumable", post(resumable))
-}
+use super::ChunkWriter;
+use crate::{
+    db_store::UploadSession,
+    net::api,
+    placement::Cluster,
+    redundancy::{Policy, Redundancy},
+};
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Path},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+    routing::{options, post},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::StreamExt;
+use uuid::Uuid;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination";
 
-#[derive(Debug, Deserialize)]
-struct Params {
-    size: u64,
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/resumable", post(create).options(capabilities))
+        .route(
+            "/resumable/{id}",
+            options(capabilities)
+                .head(offset)
+                .patch(append)
+                .delete(terminate),
+        )
+        .layer(DefaultBodyLimit::disable())
 }
 
 // TODO generalize this
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 struct RequestMetadata {
     media_name: String,
+    content_type: String,
+    replicas: Option<usize>,
+    policy: Option<Policy>,
+}
+
+impl RequestMetadata {
+    /// Parses the `Upload-Metadata` header: comma-separated `key base64(value)` pairs.
+    fn parse(header: Option<&HeaderValue>) -> Option<Self> {
+        let mut metadata = Self {
+            media_name: String::new(),
+            content_type: String::from("application/octet-stream"),
+            replicas: None,
+            policy: None,
+        };
+
+        let Some(header) = header else {
+            return Some(metadata);
+        };
+
+        for pair in header.to_str().ok()?.split(',') {
+            let mut pair = pair.trim().splitn(2, ' ');
+            let key = pair.next()?;
+            let value = BASE64.decode(pair.next().unwrap_or_default()).ok()?;
+            let value = String::from_utf8(value).ok()?;
+
+            match key {
+                "media_name" | "filename" | "name" => metadata.media_name = value,
+                "content_type" | "filetype" => metadata.content_type = super::content_type(&value)?,
+                "replicas" => metadata.replicas = Some(value.parse().ok()?),
+                "policy" => metadata.policy = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+
+        Some(metadata)
+    }
+}
+
+fn tus_response(status: StatusCode) -> axum::http::response::Builder {
+    api::response::default()
+        .status(status)
+        .header(TUS_RESUMABLE, TUS_VERSION)
+}
+
+fn tus_empty(status: StatusCode) -> (StatusCode, Response) {
+    (status, tus_response(status).body(Body::empty()).unwrap())
+}
+
+/// Rejects requests made with a version of the protocol other than the one we speak.
+fn check_version(headers: &HeaderMap) -> Option<(StatusCode, Response)> {
+    match headers.get(TUS_RESUMABLE) {
+        Some(version) if version != TUS_VERSION => {
+            let status = StatusCode::PRECONDITION_FAILED;
+            let response = tus_response(status)
+                .header(TUS_VERSION_HEADER, TUS_VERSION)
+                .body(Body::empty())
+                .unwrap();
+
+            Some((status, response))
+        }
+
+        _ => None,
+    }
+}
+
+fn parse_u64_header(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+async fn capabilities() -> (StatusCode, Response) {
+    let status = StatusCode::NO_CONTENT;
+    let response = tus_response(status)
+        .header(TUS_VERSION_HEADER, TUS_VERSION)
+        .header(TUS_EXTENSION, TUS_EXTENSIONS)
+        .body(Body::empty())
+        .unwrap();
+
+    (status, response)
+}
+
+async fn create(headers: HeaderMap) -> (StatusCode, Response) {
+    if let Some(rejection) = check_version(&headers) {
+        return rejection;
+    }
+
+    let Some(size) = parse_u64_header(&headers, &UPLOAD_LENGTH) else {
+        return tus_empty(StatusCode::BAD_REQUEST);
+    };
+
+    let Some(metadata) = RequestMetadata::parse(headers.get(UPLOAD_METADATA)) else {
+        return tus_empty(StatusCode::BAD_REQUEST);
+    };
+
+    if let Err(err) = Redundancy::resolve(metadata.policy, metadata.replicas) {
+        debug!("Rejecting upload session: {err}");
+
+        return tus_empty(StatusCode::BAD_REQUEST);
+    }
+
+    let id = Uuid::now_v7();
+
+    let db_store = crate::DB_STORE.read().await;
+    let result = db_store
+        .get()
+        .unwrap()
+        .add_upload_session(
+            id,
+            &metadata.media_name,
+            &metadata.content_type,
+            size,
+            metadata.replicas,
+            metadata.policy,
+        )
+        .await;
+    drop(db_store);
+
+    if let Err(err) = result {
+        error!("Error creating upload session: {err:?}");
+
+        return tus_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    debug!("Created upload session {id}: {metadata:?} ({size} bytes)");
+
+    let status = StatusCode::CREATED;
+    let response = tus_response(status)
+        .header("Location", format!("/api/media/upload/resumable/{id}"))
+        .header(UPLOAD_OFFSET, 0)
+        .body(Body::empty())
+        .unwrap();
+
+    (status, response)
+}
+
+async fn get_session(id: Uuid) -> Result<Option<UploadSession>> {
+    let db_store = crate::DB_STORE.read().await;
+
+    db_store.get().unwrap().get_upload_session(id).await
+}
+
+async fn offset(id: Path<Uuid>, headers: HeaderMap) -> (StatusCode, Response) {
+    if let Some(rejection) = check_version(&headers) {
+        return rejection;
+    }
+
+    match get_session(*id).await {
+        Ok(Some(session)) => {
+            let status = StatusCode::OK;
+            let response = tus_response(status)
+                .header(UPLOAD_OFFSET, session.upload_offset)
+                .header(UPLOAD_LENGTH, session.size)
+                .header("Cache-Control", "no-store")
+                .body(Body::empty())
+                .unwrap();
+
+            (status, response)
+        }
+
+        Ok(None) => tus_empty(StatusCode::NOT_FOUND),
+
+        Err(err) => {
+            error!("Error reading upload session {}: {err:?}", *id);
+
+            tus_empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn append(id: Path<Uuid>, headers: HeaderMap, body: Body) -> (StatusCode, Response) {
+    if let Some(rejection) = check_version(&headers) {
+        return rejection;
+    }
+
+    if headers.get("Content-Type").map(HeaderValue::as_bytes)
+        != Some(OFFSET_CONTENT_TYPE.as_bytes())
+    {
+        return tus_empty(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let Some(request_offset) = parse_u64_header(&headers, &UPLOAD_OFFSET) else {
+        return tus_empty(StatusCode::BAD_REQUEST);
+    };
+
+    let session = match get_session(*id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return tus_empty(StatusCode::NOT_FOUND),
+
+        Err(err) => {
+            error!("Error reading upload session {}: {err:?}", *id);
+
+            return tus_empty(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if i64::try_from(request_offset) != Ok(session.upload_offset) {
+        return tus_empty(StatusCode::CONFLICT);
+    }
+
+    // Refuse a body that declares it won't fit before storing any of it; one that turns out not
+    // to fit is refused by `append_body`.
+    let size = session.size.unsigned_abs();
+    let body_len = parse_u64_header(&headers, &header::CONTENT_LENGTH);
+    if body_len.is_some_and(|len| request_offset.saturating_add(len) > size) {
+        return tus_empty(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let cluster = match Cluster::snapshot().await {
+        Ok(cluster) => cluster,
+
+        Err(err) => {
+            error!("Error reading shard capacities: {err:?}");
+
+            return tus_empty(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let policy = session
+        .policy
+        .as_deref()
+        .and_then(|policy| policy.parse().ok());
+    let replicas = session
+        .replicas
+        .and_then(|replicas| usize::try_from(replicas).ok());
+    let redundancy = match super::redundancy(policy, replicas, &cluster) {
+        Ok(redundancy) => redundancy,
+        Err(status) => return tus_empty(status),
+    };
+
+    match append_body(&session, cluster, redundancy, body).await {
+        Ok(Appended::Offset(upload_offset)) => {
+            let status = StatusCode::NO_CONTENT;
+            let response = tus_response(status)
+                .header(UPLOAD_OFFSET, upload_offset)
+                .body(Body::empty())
+                .unwrap();
+
+            (status, response)
+        }
+
+        Ok(Appended::Conflict) => tus_empty(StatusCode::CONFLICT),
+        Ok(Appended::TooLong) => tus_empty(StatusCode::PAYLOAD_TOO_LARGE),
+
+        Err(err) => {
+            error!("Error appending to upload session {}: {err:?}", *id);
+
+            tus_empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
-async fn resumable(
-    query: Query<Params>,
-    metadata: Json<RequestMetadata>,
-) -> (StatusCode, Response) {
-    todo!()
+/// What became of the body of a `PATCH` request.
+enum Appended {
+    /// The body was recorded, and the session is now at this offset.
+    Offset(u64),
+
+    /// The session was advanced concurrently, so nothing was recorded.
+    Conflict,
+
+    /// The body runs past the session's declared length, so nothing was recorded.
+    TooLong,
+}
+
+/// Streams `body` into the session's chunks, and records the resulting progress.
+///
+/// Whatever part of the body was received before an error is still recorded, so the client can
+/// resume from it. A body that runs past the session's declared length is a mistake on the
+/// client's part rather than an interruption, so none of it is recorded.
+async fn append_body(
+    session: &UploadSession,
+    cluster: Cluster,
+    redundancy: Redundancy,
+    body: Body,
+) -> Result<Appended> {
+    let size = u64::try_from(session.size)?;
+    let start_offset = u64::try_from(session.upload_offset)?;
+    let start_seq = usize::try_from(session.chunks)?;
+
+    let mut writer = ChunkWriter::resume(cluster, redundancy, start_seq, &session.tail);
+    let mut stream = body.into_data_stream();
+    let mut error = None;
+
+    while let Some(data) = stream.next().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(err) => {
+                error = Some(anyhow!(err));
+                break;
+            }
+        };
+
+        if start_offset + writer.size() + data.len() as u64 > size {
+            return Ok(Appended::TooLong);
+        }
+
+        if let Err(err) = writer.write(&data).await {
+            error = Some(err);
+            break;
+        }
+    }
+
+    let db_store = crate::DB_STORE.read().await;
+    let db_store = db_store.get().unwrap();
+
+    if error.is_none() && start_offset + writer.size() == size {
+        let chunks = writer.finish().await?;
+
+        if !db_store.finish_upload_session(session, &chunks).await? {
+            return Ok(Appended::Conflict);
+        }
+
+        debug!("Finished upload session {}: {size} bytes", session.id);
+
+        return Ok(Appended::Offset(size));
+    }
+
+    // Derive the offset from what was actually kept, rather than what was received; a failed
+    // chunk store loses the bytes of that chunk, and everything buffered after it.
+    let (chunks, tail) = writer.suspend();
+    let stored_len: usize = chunks.iter().map(|chunk| chunk.range.len()).sum();
+    let upload_offset = start_offset - session.tail.len() as u64 + (stored_len + tail.len()) as u64;
+
+    if !db_store
+        .advance_upload_session(session, upload_offset, &tail, &chunks)
+        .await?
+    {
+        return Ok(Appended::Conflict);
+    }
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(Appended::Offset(upload_offset)),
+    }
+}
+
+async fn terminate(id: Path<Uuid>, headers: HeaderMap) -> (StatusCode, Response) {
+    if let Some(rejection) = check_version(&headers) {
+        return rejection;
+    }
+
+    let db_store = crate::DB_STORE.read().await;
+    let result = db_store.get().unwrap().delete_upload_session(*id).await;
+    drop(db_store);
+
+    match result {
+        Ok(true) => tus_empty(StatusCode::NO_CONTENT),
+        Ok(false) => tus_empty(StatusCode::NOT_FOUND),
+
+        Err(err) => {
+            error!("Error deleting upload session {}: {err:?}", *id);
+
+            tus_empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
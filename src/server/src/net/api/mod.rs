@@ -1,5 +1,7 @@
+mod admin;
 mod info;
 mod media;
+pub mod range;
 pub mod response;
 
 use anyhow::Result;
@@ -9,6 +11,7 @@ use tokio_util::sync::CancellationToken;
 
 pub async fn accept_connections(listener: TcpListener, _ctoken: &CancellationToken) -> Result<()> {
     let router = Router::new()
+        .nest("/api", admin::routes())
         .nest("/api", info::routes())
         .nest("/api", media::routes());
 
@@ -0,0 +1,151 @@
+//! Parsing of the HTTP `Range` request header (RFC 9110 §14), for `bytes` ranges only.
+
+use axum::http::HeaderValue;
+use std::ops::Range;
+
+/// Requests for more ranges than this are served in full, rather than as parts.
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// No (usable) `Range` header; the whole representation should be sent.
+    Full,
+
+    /// The satisfiable ranges, in request order, as half-open byte ranges.
+    Partial(Vec<Range<u64>>),
+
+    /// None of the requested ranges overlap the representation.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a representation of `size` bytes.
+///
+/// Headers that are malformed or use a unit other than `bytes` are ignored, as the RFC allows.
+pub fn parse(header: Option<&HeaderValue>, size: u64) -> Ranges {
+    let Some(specs) = header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.trim().strip_prefix("bytes="))
+    else {
+        return Ranges::Full;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return Ranges::Full;
+        };
+
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            // `first-last`
+            (Ok(first), Ok(last)) if first <= last => first..last.saturating_add(1),
+
+            // `first-`
+            (Ok(first), Err(_)) if last.is_empty() => first..size,
+
+            // `-suffix_len`
+            (Err(_), Ok(suffix_len)) if first.is_empty() => size.saturating_sub(suffix_len)..size,
+
+            _ => return Ranges::Full,
+        };
+
+        let range = range.start..std::cmp::min(range.end, size);
+        if !range.is_empty() {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        Ranges::Full
+    } else if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    fn parse_str(header: &str, size: u64) -> Ranges {
+        parse(Some(&HeaderValue::from_str(header).unwrap()), size)
+    }
+
+    #[test]
+    fn closed_ranges() {
+        assert_eq!(parse_str("bytes=0-99", 1000), Ranges::Partial(vec![0..100]));
+        assert_eq!(
+            parse_str("bytes=900-1999", 1000),
+            Ranges::Partial(vec![900..1000])
+        );
+        assert_eq!(parse_str("bytes=5-4", 1000), Ranges::Full);
+    }
+
+    #[test]
+    fn open_ranges() {
+        assert_eq!(
+            parse_str("bytes=100-", 1000),
+            Ranges::Partial(vec![100..1000])
+        );
+        assert_eq!(parse_str("bytes=0-", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(
+            parse_str("bytes=-100", 1000),
+            Ranges::Partial(vec![900..1000])
+        );
+        assert_eq!(
+            parse_str("bytes=-5000", 1000),
+            Ranges::Partial(vec![0..1000])
+        );
+        assert_eq!(parse_str("bytes=-0", 1000), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_kept_in_order() {
+        assert_eq!(
+            parse_str("bytes=500-599, 0-99,50-149", 1000),
+            Ranges::Partial(vec![500..600, 0..100, 50..150])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_dropped() {
+        assert_eq!(
+            parse_str("bytes=2000-2999,0-9", 1000),
+            Ranges::Partial(vec![0..10])
+        );
+        assert_eq!(parse_str("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_str("bytes=2000-2999", 1000), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn too_many_ranges_are_served_in_full() {
+        let specs = |count: u64| {
+            (0..count)
+                .map(|i| format!("{}-{}", i * 10, i * 10 + 4))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let Ranges::Partial(ranges) = parse_str(&format!("bytes={}", specs(32)), 1000) else {
+            panic!("expected partial ranges");
+        };
+        assert_eq!(ranges.len(), MAX_RANGES);
+        assert_eq!(
+            parse_str(&format!("bytes={}", specs(33)), 1000),
+            Ranges::Full
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        assert_eq!(parse(None, 1000), Ranges::Full);
+        assert_eq!(parse_str("items=0-9", 1000), Ranges::Full);
+        assert_eq!(parse_str("bytes=0-9,x", 1000), Ranges::Full);
+        assert_eq!(parse_str("bytes=-", 1000), Ranges::Full);
+    }
+}
@@ -16,3 +16,9 @@ pub fn json<T: serde::Serialize>(item: T) -> Result<Response> {
 
     Ok(builder)
 }
+
+pub fn empty() -> Result<Response> {
+    let builder = default().body(Body::empty())?;
+
+    Ok(builder)
+}
@@ -1,103 +1,802 @@
-use crate::{cfg, PEER_CTOKENS};
+use super::tls;
+use crate::{cfg, db_store::Release, gc, rebalance, repair, PEERS, PEER_CTOKENS};
 use anyhow::Result;
-use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use futures::future::try_join_all;
 use lib::{
-    error::unexpected_message,
-    net::{ping_pong, receive_message, Message, MAX_TIMEOUT, MESSAGE_TIMEOUT},
-    ShardInfo,
+    bstr::BStr,
+    chunk::Chunk,
+    net::{self, Capabilities, Connection, Message, RecvHalf, RequestError, RequestId, SendHalf},
+    noise::{self, Keypair, PublicKey, Session},
+    Hash, ShardInfo,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{btree_map::Entry, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
-use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
     io::{AsyncRead, AsyncWrite, BufStream},
-    net::{TcpListener, TcpStream},
-    time::sleep,
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    time::{interval, sleep, timeout, Instant, MissedTickBehavior},
 };
 use tokio_util::sync::CancellationToken;
 use tracing::Level;
 use uuid::Uuid;
 
+/// Maximum number of requests that may be queued for a single peer before callers must wait.
+const PEER_QUEUE_LEN: usize = 64;
+
+/// How often to check whether a deletion a store is waiting on has finished.
+const DELETE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static KEYPAIR: OnceCell<Keypair> = OnceCell::new();
+
+struct Request {
+    id: RequestId,
+    message: Message,
+}
+
+/// Requests sent to a connected shard that are awaiting their responses.
+#[derive(Debug, Default)]
+struct Pending {
+    last_id: AtomicU64,
+    responses: Mutex<HashMap<RequestId, oneshot::Sender<Message>>>,
+}
+
+impl Pending {
+    /// Allocates an ID for a new request, and returns it with the receiver of its response.
+    fn add(&self) -> (RequestId, oneshot::Receiver<Message>) {
+        // IDs start after `SETUP_ID`, so that no request is taken for part of the setup.
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (respond_to, response) = oneshot::channel();
+        self.responses.lock().unwrap().insert(id, respond_to);
+
+        (id, response)
+    }
+
+    /// Hands `message` to whoever awaits the response to the request `id`. Returns whether anyone
+    /// still was.
+    fn respond(&self, id: RequestId, message: Message) -> bool {
+        let respond_to = self.responses.lock().unwrap().remove(&id);
+
+        respond_to.is_some_and(|respond_to| respond_to.send(message).is_ok())
+    }
+
+    /// Stops waiting for the response to the request `id`.
+    fn forget(&self, id: RequestId) {
+        self.responses.lock().unwrap().remove(&id);
+    }
+
+    /// Fails every request still awaiting its response.
+    fn clear(&self) {
+        self.responses.lock().unwrap().clear();
+    }
+}
+
+/// How a connected shard has been answering pings.
+#[derive(Debug, Default)]
+struct Liveness {
+    /// Round trip time of the last ping answered, in microseconds.
+    rtt_micros: AtomicU64,
+
+    /// Number of ping intervals that have passed since the last ping was sent, without an answer.
+    missed: AtomicU32,
+}
+
+/// Handle to a connected shard, used to issue requests to it.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    id: Uuid,
+    requests: mpsc::Sender<Request>,
+    pending: Arc<Pending>,
+    liveness: Arc<Liveness>,
+    capabilities: Capabilities,
+}
+
+impl Peer {
+    #[inline]
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Whether the shard answered its last ping in time.
+    pub fn is_responsive(&self) -> bool {
+        self.liveness.missed.load(Ordering::Relaxed) == 0
+    }
+
+    /// Whether the shard agreed to use `capability` when it connected.
+    #[inline]
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Round trip time of the last ping the shard answered.
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.liveness.rtt_micros.load(Ordering::Relaxed))
+    }
+
+    /// Sends `message` to the shard, and waits for its response. Other requests may be in flight
+    /// to the shard meanwhile.
+    pub async fn request(&self, message: Message) -> Result<Message> {
+        let message_timeout = Duration::from_millis(cfg::get().timeout.message);
+
+        let (id, response) = self.submit(message).await?;
+        match timeout(message_timeout, response).await {
+            Ok(response) => response.map_err(|_| anyhow!("shard {} disconnected", self.id)),
+
+            // A late response is dropped once it arrives, so the connection can be kept.
+            Err(_) => {
+                self.pending.forget(id);
+                bail!("shard {} did not answer request {id} in time", self.id)
+            }
+        }
+    }
+
+    /// Queues `message` to be sent to the shard, and returns the ID it is sent with, and the
+    /// receiver of its response.
+    async fn submit(&self, message: Message) -> Result<(RequestId, oneshot::Receiver<Message>)> {
+        let (id, response) = self.pending.add();
+
+        if self.requests.send(Request { id, message }).await.is_err() {
+            self.pending.forget(id);
+            bail!("shard {} is disconnected", self.id);
+        }
+
+        Ok((id, response))
+    }
+
+    /// Stores `chunk` on the shard.
+    pub async fn store(&self, chunk: Chunk) -> Result<()> {
+        match self.request(Message::ShardStore { chunk }).await? {
+            Message::Ok => Ok(()),
+            Message::Error { error } => Err(error.into()),
+            message => bail!("Unexpected message (expected Message::Ok): {message:?}"),
+        }
+    }
+
+    /// Deletes the chunk `hash` from the shard, if it is stored there.
+    pub async fn delete(&self, hash: Hash) -> Result<()> {
+        match self.request(Message::ShardDelete { hash }).await? {
+            Message::Ok => Ok(()),
+            Message::Error { error } => Err(error.into()),
+            message => bail!("Unexpected message (expected Message::Ok): {message:?}"),
+        }
+    }
+
+    /// Stores every one of `chunks` on the shard in a single transaction, or none of them. The
+    /// shard must [support](Self::supports) [`Capabilities::BATCHING`].
+    pub async fn store_batch(&self, chunks: Vec<Chunk>) -> Result<()> {
+        match self.request(Message::ShardStoreBatch { chunks }).await? {
+            Message::Ok => Ok(()),
+            Message::Error { error } => Err(error.into()),
+            message => bail!("Unexpected message (expected Message::Ok): {message:?}"),
+        }
+    }
+
+    /// Retrieves the chunks `hashes` from the shard, each `None` if it isn't stored there. The
+    /// shard must [support](Self::supports) [`Capabilities::BATCHING`].
+    pub async fn retrieve_batch(&self, hashes: Vec<Hash>) -> Result<Vec<Option<Chunk>>> {
+        let len = hashes.len();
+
+        match self.request(Message::ShardRetrieveBatch { hashes }).await? {
+            Message::ShardChunkBatch { chunks } if chunks.len() == len => Ok(chunks),
+            Message::Error { error } => Err(error.into()),
+            message => bail!("Unexpected message (expected Message::ShardChunkBatch): {message:?}"),
+        }
+    }
+
+    /// Returns whether each of the chunks `hashes` is stored on the shard. The shard must
+    /// [support](Self::supports) [`Capabilities::BATCHING`].
+    pub async fn exists_batch(&self, hashes: Vec<Hash>) -> Result<Vec<bool>> {
+        let len = hashes.len();
+
+        match self
+            .request(Message::ShardChunkExistsBatch { hashes })
+            .await?
+        {
+            Message::ShardExistsBatch { bitmap } => {
+                Ok((0..len).map(|index| net::bit(&bitmap, index)).collect())
+            }
+            Message::Error { error } => Err(error.into()),
+            message => {
+                bail!("Unexpected message (expected Message::ShardExistsBatch): {message:?}")
+            }
+        }
+    }
+}
+
+/// Returns a handle to the shard with the given ID, if it is connected.
+pub async fn get(id: Uuid) -> Option<Peer> {
+    PEERS.lock().await.get(&id).cloned()
+}
+
+/// Returns a handle to every shard that is connected and answering pings in time.
+pub async fn reachable() -> Vec<Peer> {
+    PEERS
+        .lock()
+        .await
+        .values()
+        .filter(|peer| peer.is_responsive())
+        .cloned()
+        .collect()
+}
+
+/// Stores `data` as the chunk `hash` on every one of `shards` at once, failing unless all of them
+/// store it. Each store is recorded first (see [`begin_store`]).
+pub async fn store_all(shards: &[&Peer], hash: Hash, data: &[u8]) -> Result<()> {
+    try_join_all(shards.iter().map(|shard| async move {
+        begin_store(shard.id(), hash).await?;
+
+        let mut chunk = Chunk::new_zeroed(hash, data.len()).await;
+        chunk.copy_from_slice(data);
+
+        shard.store(chunk).await
+    }))
+    .await?;
+
+    Ok(())
+}
+
+/// Records that `stored_hash` is about to be stored on `shard_id`, which keeps it from being
+/// deleted there until the placement it is stored for is recorded, or the store goes stale.
+///
+/// If it is being deleted there, this waits for that to finish first, for up to the message
+/// timeout.
+pub async fn begin_store(shard_id: Uuid, stored_hash: Hash) -> Result<()> {
+    let message_timeout = Duration::from_millis(cfg::get().timeout.message);
+    let started = Instant::now();
+
+    loop {
+        let db_store = crate::DB_STORE.read().await;
+        let begun = db_store
+            .get()
+            .unwrap()
+            .begin_store(shard_id, stored_hash)
+            .await?;
+        drop(db_store);
+
+        if begun {
+            return Ok(());
+        }
+        if started.elapsed() >= message_timeout {
+            bail!("{stored_hash} is still being deleted from shard {shard_id}");
+        }
+
+        sleep(DELETE_POLL_INTERVAL).await;
+    }
+}
+
+/// Loads the server's static key, generating it on first start, and returns its public half,
+/// which shards must be configured to expect.
+pub async fn init_keypair() -> Result<PublicKey> {
+    let db_store = crate::DB_STORE.read().await;
+    let keypair = db_store
+        .get()
+        .unwrap()
+        .get_server_key(&Keypair::generate()?)
+        .await?;
+    drop(db_store);
+
+    let public = keypair.public();
+    KEYPAIR.set(keypair).expect("keypair already initialized");
+
+    Ok(public)
+}
+
+/// Returns the public half of the server's static key.
+pub fn public_key() -> PublicKey {
+    KEYPAIR
+        .get()
+        .expect("keypair has not been initialized")
+        .public()
+}
+
+/// Fetches a chunk from the first of `shard_ids` that is connected and responds with an intact
+/// copy of it, trying responsive shards with the shortest round trip time first.
+pub async fn fetch(hash: Hash, shard_ids: &[Uuid]) -> Result<Chunk> {
+    let peers = PEERS.lock().await;
+    let mut shards = shard_ids
+        .iter()
+        .filter_map(|shard_id| peers.get(shard_id).cloned())
+        .collect::<Vec<_>>();
+    drop(peers);
+
+    shards.sort_by_key(|shard| (!shard.is_responsive(), shard.rtt()));
+
+    for shard in shards {
+        let shard_id = shard.id();
+
+        match shard.request(Message::ShardRetrieve { hash }).await {
+            Ok(Message::ShardChunk { chunk }) if Hash::of(&chunk) == hash => return Ok(chunk),
+
+            // Left for the shard's scrubbing to find and report.
+            Ok(Message::ShardChunk { .. }) => {
+                warn!("Shard {shard_id} returned a corrupt copy of chunk {hash}");
+            }
+
+            Ok(Message::Error {
+                error: RequestError::NotFound,
+            }) => {
+                debug!("Chunk {hash} is not stored on shard {shard_id}");
+            }
+
+            Ok(message) => {
+                warn!(
+                    "Unexpected response retrieving chunk {hash} from shard {shard_id}: {message:?}"
+                );
+            }
+
+            Err(err) => {
+                warn!("Error retrieving chunk {hash} from shard {shard_id}: {err:?}");
+            }
+        }
+    }
+
+    bail!("chunk {hash} is not available from any shard")
+}
+
 #[instrument(skip(listener, ctoken))]
 pub async fn accept_connections(listener: TcpListener, ctoken: &CancellationToken) -> Result<()> {
+    let acceptor = tls::acceptor()?;
+    if acceptor.is_some() {
+        info!("Shards must present certificates from the configured CA");
+    }
+
     loop {
         trace!("Waiting to accept shard...");
         let (peer_socket, peer_address) = listener.accept().await?;
         let peer_ctoken = ctoken.child_token();
+        let acceptor = acceptor.clone();
 
         tokio::spawn(async move {
-            let (peer_stream, peer_cipher, peer_id) =
-                spawn_peer(peer_address, peer_socket, &peer_ctoken)
-                    .await
-                    .expect("error spawning peer");
+            let Some(acceptor) = acceptor else {
+                serve_peer(peer_address, peer_socket, None, peer_ctoken).await;
+                return;
+            };
 
-            listen_peer(peer_stream, peer_cipher, peer_id, peer_ctoken)
+            let message_timeout = Duration::from_millis(cfg::get().timeout.message);
+            let accepted = timeout(message_timeout, acceptor.accept(peer_socket))
                 .await
-                .expect("error listening to peer");
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| {
+                    let stream = stream?;
+                    let id = tls::shard_id(stream.get_ref().1)?;
+
+                    Ok((stream, id))
+                });
+
+            match accepted {
+                Ok((stream, id)) => serve_peer(peer_address, stream, Some(id), peer_ctoken).await,
+                Err(err) => error!("Error accepting TLS from peer {peer_address}: {err:?}"),
+            }
         });
     }
 }
 
+/// Sets up a newly accepted shard connection, and serves it until it closes. `certified` is the
+/// shard ID its TLS certificate names, if it presented one.
+async fn serve_peer<IO: AsyncRead + AsyncWrite + Unpin>(
+    address: SocketAddr,
+    stream: IO,
+    certified: Option<Uuid>,
+    ctoken: CancellationToken,
+) {
+    let (connection, peer, requests) = match spawn_peer(address, stream, certified, &ctoken).await {
+        Ok(peer) => peer,
+        Err(err) => {
+            error!("Error spawning peer {address}: {err:?}");
+            return;
+        }
+    };
+
+    rebalance::wake();
+
+    let peer_id = peer.id();
+    if let Err(err) = listen_peer(connection, peer, requests, ctoken).await {
+        error!("Error listening to peer {peer_id}: {err:?}");
+    }
+
+    PEERS.lock().await.remove(&peer_id);
+    PEER_CTOKENS.lock().await.remove(&peer_id);
+
+    let db_store = crate::DB_STORE.read().await;
+    if let Err(err) = db_store.get().unwrap().mark_offline(peer_id).await {
+        error!("Error marking peer {peer_id} offline: {err:?}");
+    }
+    drop(db_store);
+
+    rebalance::wake();
+}
+
 #[instrument(skip(socket, ctoken))]
-async fn spawn_peer(
+async fn spawn_peer<IO: AsyncRead + AsyncWrite + Unpin>(
     address: SocketAddr,
-    socket: TcpStream,
+    socket: IO,
+    certified: Option<Uuid>,
     ctoken: &CancellationToken,
-) -> Result<(BufStream<TcpStream>, Arc<XChaCha20Poly1305>, Uuid)> {
-    let mut stream = BufStream::new(socket);
-    let cipher = {
-        let key = lib::crypto::ecdh_handshake(&mut stream).await?;
-        let cipher = XChaCha20Poly1305::new(&key.into());
+) -> Result<(Connection<BufStream<IO>>, Peer, mpsc::Receiver<Request>)> {
+    let message_timeout = Duration::from_millis(cfg::get().timeout.message);
+
+    let mut socket = socket;
+    let keypair = KEYPAIR.get().expect("keypair has not been initialized");
+    let session = timeout(message_timeout, noise::respond(&mut socket, keypair)).await??;
 
-        Arc::new(cipher)
+    let mut connection = Connection::new(BufStream::new(socket), session.keys.clone());
+    let (version, capabilities) = hello(&mut connection, message_timeout).await?;
+    let id = identify(&mut connection, &session, certified, message_timeout).await?;
+
+    let server_info = Message::ServerInfo {
+        agent: BStr::new(crate::agent()),
     };
+    expect_ok(request(&mut connection, server_info, message_timeout).await?)?;
 
-    lib::net::negotiate_hello(&mut stream, &cipher).await?;
+    let info = match timeout(message_timeout, connection.recv()).await?? {
+        Message::ShardInfo {
+            chunks,
+            agent,
+            labels,
+        } => ShardInfo::new(id, agent.to_string(), chunks, labels.domain()),
+        message => bail!("Unexpected message (expected Message::ShardInfo): {message:?}"),
+    };
 
-    let info = match receive_message(&mut stream, &cipher, MESSAGE_TIMEOUT).await? {
-        Message::ShardInfo { id, agent, chunks } => Ok(ShardInfo::new(id, agent, chunks)),
-        message => unexpected_message("Message::Info", message),
-    }?;
+    event!(
+        Level::DEBUG,
+        id = ?info.id(),
+        agent = ?info.agent(),
+        chunks = ?info.chunks(),
+        domain = ?info.domain(),
+        version,
+        capabilities = ?capabilities
+    );
 
-    event!(Level::DEBUG, id = ?info.id(), agent = ?info.agent(), chunks = ?info.chunks());
+    let db_store = crate::DB_STORE.read().await;
+    db_store.get().unwrap().add_shard(&info).await?;
+    drop(db_store);
 
-    let mut peer_ctokens = PEER_CTOKENS.lock().await;
-    peer_ctokens.insert(info.id(), ctoken.clone());
-    drop(peer_ctokens);
+    let (requests_tx, requests_rx) = mpsc::channel(PEER_QUEUE_LEN);
+    let peer = Peer {
+        id,
+        requests: requests_tx,
+        pending: Arc::default(),
+        liveness: Arc::default(),
+        capabilities,
+    };
 
-    let pg_pool_read = crate::DB_STORE.read().await;
-    pg_pool_read.get().unwrap().add_shard(&info).await?;
-    drop(pg_pool_read);
+    // Another connection may have claimed the ID since it was checked.
+    match PEERS.lock().await.entry(id) {
+        Entry::Vacant(entry) => entry.insert(peer.clone()),
+        Entry::Occupied(_) => bail!("Shard {id} connected twice at once; disconnecting."),
+    };
+    PEER_CTOKENS.lock().await.insert(id, ctoken.clone());
 
     debug!("Connected.");
 
-    Ok((stream, cipher, info.id()))
+    Ok((connection, peer, requests_rx))
 }
 
-#[instrument(skip(ctoken, stream, cipher))]
-async fn listen_peer<S: AsyncRead + AsyncWrite + Unpin, C: AsRef<XChaCha20Poly1305>>(
-    mut stream: S,
-    cipher: C,
-    id: Uuid,
+/// Agrees with the shard on the protocol version to speak, the highest both support, and the
+/// capabilities to use. Fails if they share no version.
+async fn hello<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<IO>,
+    message_timeout: Duration,
+) -> Result<(u16, Capabilities)> {
+    let (min_version, max_version, capabilities) =
+        match timeout(message_timeout, connection.recv_hello()).await?? {
+            Message::Hello {
+                min_version,
+                max_version,
+                capabilities,
+            } => (min_version, max_version, capabilities),
+            message => bail!("Unexpected message (expected Message::Hello): {message:?}"),
+        };
+
+    let Some(version) = net::negotiate_version(min_version, max_version) else {
+        let refusal = Message::Error {
+            error: RequestError::Incompatible,
+        };
+        timeout(message_timeout, connection.send_hello(refusal)).await??;
+        bail!(
+            "Shard speaks protocol versions {min_version}..={max_version}, but the server only \
+             speaks {}..={}; disconnecting.",
+            net::MIN_PROTOCOL_VERSION,
+            net::MAX_PROTOCOL_VERSION
+        );
+    };
+
+    let capabilities = capabilities.intersection(Capabilities::SUPPORTED);
+    let hello = Message::Hello {
+        min_version: version,
+        max_version: version,
+        capabilities,
+    };
+    timeout(message_timeout, connection.send_hello(hello)).await??;
+
+    Ok((version, capabilities))
+}
+
+/// Learns the shard's ID: the one its static key is enrolled for, or a new one it is assigned as
+/// it enrolls with a token. A shard that presented a certificate can only have the ID it names.
+///
+/// Fails if the shard's key isn't enrolled (or is enrolled for another ID than it presents or its
+/// certificate names), or if another shard is connected with the same ID, since two shards can't
+/// hold the same chunks.
+async fn identify<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<IO>,
+    session: &Session,
+    certified: Option<Uuid>,
+    message_timeout: Duration,
+) -> Result<Uuid> {
+    let presented = match timeout(message_timeout, connection.recv()).await?? {
+        Message::ShardIdentify { id } => id,
+        message => bail!("Unexpected message (expected Message::ShardIdentify): {message:?}"),
+    };
+
+    if certified.is_some_and(|certified| presented.is_some_and(|id| id != certified)) {
+        refuse(connection, RequestError::Unauthorized, message_timeout).await?;
+        bail!("Shard presented ID {presented:?}, but its certificate names {certified:?}");
+    }
+
+    // A shard sends its enrollment token, if it has one, as the handshake's last payload.
+    let token_hash = Some(&session.payload)
+        .filter(|token| !token.is_empty())
+        .map(|token| Hash::of(token));
+
+    let db_store = crate::DB_STORE.read().await;
+    let authorized = db_store
+        .get()
+        .unwrap()
+        .authorize_shard(
+            session.remote,
+            presented,
+            token_hash,
+            certified.unwrap_or_else(Uuid::now_v7),
+        )
+        .await?;
+    drop(db_store);
+
+    let authorized = authorized.filter(|&id| certified.is_none_or(|certified| id == certified));
+    let Some(id) = authorized else {
+        refuse(connection, RequestError::Unauthorized, message_timeout).await?;
+        bail!(
+            "Shard key {} is not enrolled as {presented:?}; disconnecting.",
+            session.remote
+        );
+    };
+
+    if get(id).await.is_some() {
+        refuse(connection, RequestError::DuplicateId, message_timeout).await?;
+        bail!("Shard {id} is already connected; disconnecting.");
+    }
+
+    if presented == Some(id) {
+        timeout(message_timeout, connection.send(Message::Ok, true)).await??;
+    } else {
+        expect_ok(request(connection, Message::AssignId { id }, message_timeout).await?)?;
+        debug!("Enrolled new shard {id}");
+    }
+
+    Ok(id)
+}
+
+async fn refuse<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<IO>,
+    error: RequestError,
+    message_timeout: Duration,
+) -> Result<()> {
+    timeout(
+        message_timeout,
+        connection.send(Message::Error { error }, true),
+    )
+    .await??;
+
+    Ok(())
+}
+
+/// Serves the requests made of a connected shard until it disconnects, or is to be disconnected.
+///
+/// Requests are sent as they are made, without waiting for the responses to earlier ones, and
+/// each response is handed to the request it names.
+#[instrument(skip_all, fields(id = %peer.id()))]
+async fn listen_peer<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: Connection<IO>,
+    peer: Peer,
+    requests: mpsc::Receiver<Request>,
     ctoken: CancellationToken,
 ) -> Result<()> {
-    let ping_wait = Duration::from_millis(cfg::get().interval.ping);
+    let (reader, writer) = connection.split();
 
-    'a: loop {
-        tokio::select! {
-            _ = ctoken.cancelled() => break 'a,
+    let result = tokio::select! {
+        _ = ctoken.cancelled() => Ok(()),
 
-            _ = sleep(ping_wait) => ping_pong(&mut stream, &cipher).await,
+        result = send_requests(writer, requests) => result,
+        result = receive_responses(reader, &peer) => result,
+        result = keep_alive(&peer) => result,
+    };
+
+    peer.pending.clear();
+
+    result
+}
 
-            message = receive_message(&mut stream, &cipher, MAX_TIMEOUT) => {
-                match message {
-                    Ok(Message::ShardShutdown) => break 'a,
+async fn send_requests<W: AsyncWrite + Unpin>(
+    mut writer: SendHalf<W>,
+    mut requests: mpsc::Receiver<Request>,
+) -> Result<()> {
+    let message_timeout = Duration::from_millis(cfg::get().timeout.message);
 
-                    Ok(message) => unexpected_message("Message::ShardShutdown", message),
+    while let Some(Request { id, message }) = requests.recv().await {
+        // Only flush once no more requests are queued, so that those made together go out
+        // together.
+        let flush = requests.is_empty();
+        timeout(message_timeout, writer.send(id, message, flush)).await??;
+    }
 
-                    Err(err) => bail!("Error reading message from pipe: {err:?}"),
+    Ok(())
+}
+
+async fn receive_responses<R: AsyncRead + Unpin>(
+    mut reader: RecvHalf<R>,
+    peer: &Peer,
+) -> Result<()> {
+    loop {
+        let (request_id, message) = reader.recv().await?;
+
+        if !peer.pending.respond(request_id, message) {
+            debug!("Peer {} answered request {request_id} too late", peer.id);
+        }
+    }
+}
+
+/// Pings the shard every ping interval, and handles what it reports in its answers.
+async fn keep_alive(peer: &Peer) -> Result<()> {
+    let mut ping_interval = interval(Duration::from_millis(cfg::get().interval.ping));
+    ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ping_interval.tick().await;
+
+        match ping(peer).await? {
+            Message::Pong => {}
+            message => bail!("Unexpected message (expected Message::Pong): {message:?}"),
+        }
+
+        if let Err(err) = handle_corrupt(peer).await {
+            error!("Error handling corrupt chunks on peer {}: {err:?}", peer.id);
+        }
+    }
+}
+
+/// Asks the shard which chunks it has found corrupt, deals with them, and acknowledges those it
+/// dealt with, so that the shard keeps reporting any that weren't.
+async fn handle_corrupt(peer: &Peer) -> Result<()> {
+    let hashes = match peer.request(Message::ShardListCorrupt).await? {
+        Message::ShardCorrupt { hashes } => hashes,
+        message => bail!("Unexpected message (expected Message::ShardCorrupt): {message:?}"),
+    };
+    if hashes.is_empty() {
+        return Ok(());
+    }
+
+    let handled = drop_corrupt(peer.id, hashes).await;
+    if handled.is_empty() {
+        return Ok(());
+    }
+
+    expect_ok(
+        peer.request(Message::ShardCorruptHandled { hashes: handled })
+            .await?,
+    )
+}
+
+/// Pings the shard, and records how long it took to answer.
+///
+/// Every ping interval that passes without an answer counts as a missed pong, during which the
+/// shard is not [responsive](Peer::is_responsive); the shard is given up on once it has missed
+/// as many as configured.
+async fn ping(peer: &Peer) -> Result<Message> {
+    let id = peer.id;
+    let period = Duration::from_millis(cfg::get().interval.ping);
+    let max_missed = cfg::get().liveness.missed;
+
+    let sent = Instant::now();
+    let (request_id, mut response) = peer.submit(Message::Ping).await?;
+
+    let response = loop {
+        match timeout(period, &mut response).await {
+            Ok(response) => break response.map_err(|_| anyhow!("peer {id} disconnected"))?,
+
+            Err(_) => {
+                let missed = peer.liveness.missed.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("Peer {id} has missed {missed} pongs");
+
+                if missed >= max_missed {
+                    peer.pending.forget(request_id);
+                    bail!("Peer missed {missed} pongs; disconnecting.");
                 }
             }
-        }?;
+        }
+    };
+
+    let rtt = sent.elapsed();
+    peer.liveness.missed.store(0, Ordering::Relaxed);
+    peer.liveness
+        .rtt_micros
+        .store(u64::try_from(rtt.as_micros())?, Ordering::Relaxed);
+
+    let db_store = crate::DB_STORE.read().await;
+    if let Err(err) = db_store.get().unwrap().record_pong(id, rtt).await {
+        error!("Error recording pong from peer {id}: {err:?}");
     }
+    drop(db_store);
 
-    Ok(())
+    Ok(response)
+}
+
+/// Forgets and deletes the chunks the shard `shard_id` reported corrupt, and has them repaired
+/// from the copies on other shards. Returns those that were forgotten.
+async fn drop_corrupt(shard_id: Uuid, hashes: Vec<Hash>) -> Vec<Hash> {
+    warn!("Shard {shard_id} reported {} corrupt chunks", hashes.len());
+
+    let mut handled = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        let db_store = crate::DB_STORE.read().await;
+        let dropped = db_store
+            .get()
+            .unwrap()
+            .drop_stored_placements(shard_id, hash)
+            .await;
+        drop(db_store);
+
+        let release = match dropped {
+            Ok((dropped, release)) => {
+                trace!("Dropped {dropped} placements of corrupt chunk {hash}");
+                release
+            }
+            Err(err) => {
+                error!("Error dropping corrupt chunk {hash} on shard {shard_id}: {err:?}");
+                continue;
+            }
+        };
+
+        handled.push(hash);
+
+        // Otherwise it is being stored there again, which replaces the corrupt copy.
+        if release != Release::Delete(hash) {
+            continue;
+        }
+        if let Some(shard) = get(shard_id).await {
+            // A deletion that fails stays recorded, and is retried by garbage collection.
+            if let Err(err) = gc::delete(&shard, hash).await {
+                warn!("Error deleting corrupt chunk {hash} from shard {shard_id}: {err:?}");
+            }
+        }
+    }
+
+    repair::wake();
+
+    handled
+}
+
+async fn request<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<IO>,
+    message: Message,
+    message_timeout: Duration,
+) -> Result<Message> {
+    timeout(message_timeout, connection.send(message, true)).await??;
+    let response = timeout(message_timeout, connection.recv()).await??;
+
+    Ok(response)
+}
+
+fn expect_ok(message: Message) -> Result<()> {
+    match message {
+        Message::Ok => Ok(()),
+        message => bail!("Unexpected message (expected Message::Ok): {message:?}"),
+    }
 }
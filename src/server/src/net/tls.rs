@@ -0,0 +1,209 @@
+//! Mutual TLS for the shard listener.
+//!
+//! When configured, every shard must present a certificate issued by our own CA, whose subject's
+//! common name is the shard's ID. That ID must then match the one the shard identifies as.
+
+use crate::cfg;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ServerConnection, WebPkiClientVerifier},
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use uuid::Uuid;
+use webpki::EndEntityCert;
+
+/// The object identifier of the common name attribute (2.5.4.3).
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Builds the acceptor for shard connections, or `None` if TLS is not configured.
+pub fn acceptor() -> Result<Option<TlsAcceptor>> {
+    let Some(tls) = &cfg::get().tls else {
+        return Ok(None);
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&tls.ca).context("failed to read CA bundle")? {
+        roots.add(cert?)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .context("failed to read certificate")?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key).context("failed to read private key")?;
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Returns the shard ID named by the common name of the certificate the shard presented.
+pub fn shard_id(connection: &ServerConnection) -> Result<Uuid> {
+    let cert = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .context("shard presented no certificate")?;
+    let cert = EndEntityCert::try_from(cert)?;
+
+    let common_name = common_name(cert.subject()).context("certificate has no common name")?;
+    let id = Uuid::parse_str(common_name)
+        .with_context(|| format!("certificate common name {common_name:?} is not a shard ID"))?;
+
+    Ok(id)
+}
+
+/// Finds the common name in a DER encoded distinguished name: a sequence of sets of attribute
+/// type and value pairs.
+fn common_name(mut name: &[u8]) -> Option<&str> {
+    while !name.is_empty() {
+        let (set, rest) = der_value(name, 0x31)?;
+        name = rest;
+
+        let mut set = set;
+        while !set.is_empty() {
+            let (attribute, rest) = der_value(set, 0x30)?;
+            set = rest;
+
+            let (oid, value) = der_value(attribute, 0x06)?;
+            if oid != COMMON_NAME_OID {
+                continue;
+            }
+
+            // UTF8String, PrintableString or IA5String.
+            let (&tag, _) = value.split_first()?;
+            if ![0x0c, 0x13, 0x16].contains(&tag) {
+                return None;
+            }
+
+            let (value, _) = der_value(value, tag)?;
+            return std::str::from_utf8(value).ok();
+        }
+    }
+
+    None
+}
+
+/// Splits a DER value with the tag `tag` off the front of `der`, returning its contents and what
+/// follows it.
+fn der_value(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found, der) = der.split_first()?;
+    if found != tag {
+        return None;
+    }
+
+    let (&len, mut der) = der.split_first()?;
+    let len = if len < 0x80 {
+        usize::from(len)
+    } else {
+        let (len_bytes, rest) = der.split_at_checked(usize::from(len & 0x7f))?;
+        der = rest;
+
+        len_bytes.iter().try_fold(0usize, |len, &byte| {
+            len.checked_mul(256)?.checked_add(byte.into())
+        })?
+    };
+
+    der.split_at_checked(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a DER value with the tag `tag`.
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag];
+        match u8::try_from(contents.len()) {
+            Ok(len) if len < 0x80 => der.push(len),
+            Ok(len) => der.extend([0x81, len]),
+            Err(_) => {
+                der.push(0x82);
+                der.extend(u16::try_from(contents.len()).unwrap().to_be_bytes());
+            }
+        }
+        der.extend(contents);
+
+        der
+    }
+
+    /// Encodes the contents of a distinguished name with one attribute per set.
+    fn name(attributes: &[(&[u8], u8, &str)]) -> Vec<u8> {
+        attributes
+            .iter()
+            .flat_map(|&(oid, tag, value)| {
+                let attribute = [der(0x06, oid), der(tag, value.as_bytes())].concat();
+                der(0x31, &der(0x30, &attribute))
+            })
+            .collect()
+    }
+
+    const COUNTRY_OID: &[u8] = &[0x55, 0x04, 0x06];
+    const ORGANIZATION_OID: &[u8] = &[0x55, 0x04, 0x0a];
+
+    #[test]
+    fn finds_the_common_name() {
+        let id = "0192d6c4-3f2a-7c1e-9b4d-5e6f7a8b9c0d";
+        let name = name(&[
+            (COUNTRY_OID, 0x13, "NL"),
+            (ORGANIZATION_OID, 0x0c, "Cluster"),
+            (COMMON_NAME_OID, 0x0c, id),
+        ]);
+
+        assert_eq!(common_name(&name), Some(id));
+    }
+
+    #[test]
+    fn accepts_string_types() {
+        for tag in [0x0c, 0x13, 0x16] {
+            assert_eq!(
+                common_name(&name(&[(COMMON_NAME_OID, tag, "shard")])),
+                Some("shard")
+            );
+        }
+
+        // BMPString.
+        assert_eq!(
+            common_name(&name(&[(COMMON_NAME_OID, 0x1e, "shard")])),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_long_form_lengths() {
+        for len in [200, 1000] {
+            let value = "x".repeat(len);
+
+            assert_eq!(
+                common_name(&name(&[(COMMON_NAME_OID, 0x0c, &value)])),
+                Some(value.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_names() {
+        assert_eq!(common_name(&[]), None);
+        assert_eq!(common_name(&name(&[(COUNTRY_OID, 0x13, "NL")])), None);
+
+        let name = name(&[(COMMON_NAME_OID, 0x0c, "shard")]);
+        for len in 0..name.len() {
+            assert_eq!(common_name(&name[..len]), None);
+        }
+
+        // A length that overflows.
+        assert_eq!(
+            der_value(
+                &[0x31, 0x89, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+                0x31
+            ),
+            None
+        );
+    }
+}
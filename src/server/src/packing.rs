@@ -0,0 +1,256 @@
+//! Packing of small media into shared "pack" chunks.
+//!
+//! Giving every small media a chunk of its own would make per-chunk overhead dominate, so media
+//! no larger than the packing threshold are queued briefly, and stored together in a single chunk.
+//! Packs are never modified once stored; a pack whose media have mostly been deleted is compacted
+//! by repacking the media still in it, which leaves the old pack unreferenced.
+//!
+//! A pack is stored on as many shards as the media in it that asks for the most.
+
+use crate::{
+    cfg,
+    db_store::{PlacedChunk, StoredChunk},
+    net::shards,
+    placement::Cluster,
+};
+use anyhow::Result;
+use futures::future::join_all;
+use lib::{chunk::Chunk, Hash};
+use once_cell::sync::OnceCell;
+use std::time::Duration;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{interval, sleep, MissedTickBehavior},
+};
+
+/// Maximum number of media that may be queued for packing before callers must wait.
+const QUEUE_LEN: usize = 256;
+
+static QUEUE: OnceCell<mpsc::Sender<Object>> = OnceCell::new();
+
+struct Object {
+    data: Vec<u8>,
+    replicas: usize,
+    respond_to: oneshot::Sender<Result<PlacedChunk>>,
+}
+
+/// Starts packing queued media, and periodically compacting sparse packs.
+pub fn start() {
+    let (queue, objects) = mpsc::channel(QUEUE_LEN);
+    QUEUE.set(queue).expect("packing already started");
+
+    tokio::spawn(pack_objects(objects));
+    tokio::spawn(compact_packs());
+}
+
+/// Whether media of `len` bytes should be packed.
+pub fn fits(len: usize) -> bool {
+    len > 0 && len <= std::cmp::min(cfg::get().packing.threshold, Chunk::SIZE)
+}
+
+/// Stores `data` in a pack on at least `replicas` shards, and returns the part of the pack that
+/// holds it.
+pub async fn pack(data: Vec<u8>, replicas: usize) -> Result<PlacedChunk> {
+    let (respond_to, packed) = oneshot::channel();
+
+    QUEUE
+        .get()
+        .expect("packing has not been started")
+        .send(Object {
+            data,
+            replicas,
+            respond_to,
+        })
+        .await
+        .map_err(|_| anyhow!("packing has stopped"))?;
+
+    packed
+        .await
+        .map_err(|_| anyhow!("packing dropped the media"))?
+}
+
+async fn pack_objects(mut queue: mpsc::Receiver<Object>) {
+    let linger = Duration::from_millis(cfg::get().packing.linger);
+
+    let mut next = None;
+    loop {
+        let first = match next.take() {
+            Some(object) => object,
+            None => match queue.recv().await {
+                Some(object) => object,
+                None => break,
+            },
+        };
+
+        // Collect media until the pack is full, or no more arrive in time.
+        let mut len = first.data.len();
+        let mut objects = vec![first];
+
+        let deadline = sleep(linger);
+        tokio::pin!(deadline);
+        while len < Chunk::SIZE {
+            tokio::select! {
+                _ = &mut deadline => break,
+
+                object = queue.recv() => match object {
+                    Some(object) if len + object.data.len() <= Chunk::SIZE => {
+                        len += object.data.len();
+                        objects.push(object);
+                    }
+
+                    Some(object) => {
+                        next = Some(object);
+                        break;
+                    }
+
+                    None => break,
+                },
+            }
+        }
+
+        match store_pack(&objects, len).await {
+            Ok(hash) => {
+                trace!(
+                    "Packed {} media into chunk {hash} ({len} bytes)",
+                    objects.len()
+                );
+
+                let mut offset = 0;
+                for object in objects {
+                    let range = offset..(offset + object.data.len());
+                    offset = range.end;
+
+                    let _ = object.respond_to.send(Ok(PlacedChunk {
+                        hash,
+                        length: len,
+                        range,
+                        coding: None,
+                        replicas: object.replicas,
+                        placements: Vec::new(),
+                    }));
+                }
+            }
+
+            Err(err) => {
+                error!("Error storing pack: {err:?}");
+
+                for object in objects {
+                    let _ = object.respond_to.send(Err(anyhow!("failed to store pack")));
+                }
+            }
+        }
+    }
+}
+
+/// Stores the concatenation of `objects` (`len` bytes) as a chunk, and records it as a pack.
+async fn store_pack(objects: &[Object], len: usize) -> Result<Hash> {
+    let replicas = objects
+        .iter()
+        .map(|object| object.replicas)
+        .max()
+        .unwrap_or(1);
+
+    let data = objects
+        .iter()
+        .flat_map(|object| object.data.iter().copied())
+        .collect::<Vec<u8>>();
+    let hash = Hash::of(&data);
+
+    let cluster = Cluster::snapshot().await?;
+    let targets = cluster.place(hash, replicas, &[]);
+    if targets.len() < replicas {
+        bail!("not enough failure domains have room to store a pack on {replicas} shards");
+    }
+
+    shards::store_all(&targets, hash, &data).await?;
+
+    let shard_ids = targets.iter().map(|shard| shard.id()).collect::<Vec<_>>();
+
+    let db_store = crate::DB_STORE.read().await;
+    db_store
+        .get()
+        .unwrap()
+        .add_pack(hash, len, &shard_ids)
+        .await?;
+
+    Ok(hash)
+}
+
+async fn compact_packs() {
+    let compaction = &cfg::get().packing.compaction;
+
+    let mut interval = interval(Duration::from_millis(compaction.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let db_store = crate::DB_STORE.read().await;
+        let packs = db_store
+            .get()
+            .unwrap()
+            .get_sparse_packs(compaction.live)
+            .await;
+        drop(db_store);
+
+        let packs = match packs {
+            Ok(packs) => packs,
+            Err(err) => {
+                error!("Error finding packs to compact: {err:?}");
+                continue;
+            }
+        };
+
+        for sparse in packs {
+            if let Err(err) = compact(&sparse).await {
+                error!("Error compacting pack {}: {err:?}", sparse.hash);
+            }
+        }
+    }
+}
+
+/// Repacks the media still referenced in the pack `sparse`.
+async fn compact(sparse: &StoredChunk) -> Result<()> {
+    let chunk = shards::fetch(sparse.hash, &sparse.shard_ids).await?;
+
+    let db_store = crate::DB_STORE.read().await;
+    let objects = db_store
+        .get()
+        .unwrap()
+        .get_packed_objects(sparse.hash)
+        .await?;
+    drop(db_store);
+
+    // Keep at least as many replicas as the old pack had.
+    let replicas = std::cmp::max(sparse.shard_ids.len(), cfg::get().replication.factor);
+
+    // Queue every object at once, so they share as few new packs as possible.
+    let repacked = join_all(objects.iter().map(|object| {
+        let offset = object.offset.unsigned_abs() as usize;
+        let length = object.length.unsigned_abs() as usize;
+
+        pack(chunk[offset..(offset + length)].to_vec(), replicas)
+    }))
+    .await;
+
+    let db_store = crate::DB_STORE.read().await;
+    let db_store = db_store.get().unwrap();
+
+    let mut moved = 0;
+    for (object, placed) in objects.iter().zip(repacked) {
+        if db_store
+            .repack_object(object, sparse.hash, &placed?)
+            .await?
+        {
+            moved += 1;
+        }
+    }
+
+    debug!(
+        "Compacted pack {}: repacked {moved} of {} media",
+        sparse.hash,
+        objects.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,310 @@
+//! Choosing which shards to store a chunk on.
+//!
+//! Shards are ranked for each chunk by weighted rendezvous (highest random weight) hashing: every
+//! shard gets a pseudo-random score from the chunk's hash and its own ID, scaled by its total
+//! capacity, and the chunk goes to the highest scoring shards. Shards that are offline, not
+//! answering pings, draining or full are never chosen.
+//!
+//! No two replicas or pieces of a chunk are placed in the same failure domain, at the configured
+//! level of the hierarchy shards report their place in; a shard that leaves its label at that
+//! level unset is a domain of its own. Where a domain has several shards, the chunk goes to the
+//! highest scoring of them.
+//!
+//! Shards are weighted by their total capacity rather than their free capacity. Weighting by free
+//! capacity would steer new chunks away from fuller shards, but it would also change every
+//! chunk's ranking with each chunk stored, so that [`rebalance`](crate::rebalance) would keep
+//! finding chunks off their targets and moving them. Instead, scores depend only on the chunk's
+//! hash and the shards' IDs and configured capacities: storing chunks doesn't change where other
+//! chunks rank, adding or removing a shard only moves the chunks that rank it (or now rank it)
+//! highest, and fill differences are left to rebalancing to even out.
+//!
+//! Which shards are skipped as full or unreachable does change over time, so a chunk's location
+//! can't be recomputed from its hash alone; where each chunk was placed is recorded.
+
+use crate::{
+    cfg::{self, DomainLevel},
+    db_store::ShardState,
+    net::shards::{self, Peer},
+};
+use anyhow::Result;
+use lib::{FailureDomain, Hash};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// A shard that chunks can be placed on.
+#[derive(Debug, Clone)]
+struct Candidate {
+    peer: Peer,
+
+    /// Number of chunks the shard can hold in total.
+    capacity: u64,
+}
+
+/// A failure domain at the configured level.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Domain {
+    Labeled(Vec<String>),
+    Unlabeled(Uuid),
+}
+
+impl Domain {
+    fn new(level: DomainLevel, shard_id: Uuid, domain: &FailureDomain) -> Self {
+        let depth = match level {
+            DomainLevel::Shard => return Self::Unlabeled(shard_id),
+            DomainLevel::Host => 3,
+            DomainLevel::Rack => 2,
+            DomainLevel::Zone => 1,
+        };
+
+        // A rack is only unique within its zone, and a host within its rack.
+        let labels = &[&domain.zone, &domain.rack, &domain.host][..depth];
+
+        match labels.last() {
+            Some(Some(_)) => Self::Labeled(
+                labels
+                    .iter()
+                    .map(|label| label.as_deref().unwrap_or_default().to_string())
+                    .collect(),
+            ),
+
+            _ => Self::Unlabeled(shard_id),
+        }
+    }
+}
+
+/// The shards that are reachable, online and not full, as of when it was taken.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    candidates: Vec<Candidate>,
+
+    /// The recorded shards that are draining.
+    draining: HashSet<Uuid>,
+
+    /// The failure domain of every recorded shard, including those chunks can't be placed on.
+    domains: HashMap<Uuid, Domain>,
+
+    /// The fraction of its capacity every recorded shard has used.
+    fills: HashMap<Uuid, f64>,
+}
+
+impl Cluster {
+    /// Takes the current state of the cluster: the shards that are reachable, and the state,
+    /// capacity and failure domain recorded for each.
+    pub async fn snapshot() -> Result<Self> {
+        let peers = shards::reachable().await;
+
+        let db_store = crate::DB_STORE.read().await;
+        let records = db_store.get().unwrap().get_shards().await?;
+        drop(db_store);
+
+        let level = cfg::get().placement.domain;
+        let domains = records
+            .iter()
+            .map(|record| (record.id, Domain::new(level, record.id, &record.domain())))
+            .collect();
+        let fills = records
+            .iter()
+            .map(|record| {
+                let fill = match record.max_chunks {
+                    0 => 1.0,
+                    max_chunks => record.chunks as f64 / max_chunks as f64,
+                };
+
+                (record.id, fill)
+            })
+            .collect();
+        let states = records
+            .iter()
+            .map(|record| Ok((record.id, record.state()?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let mut candidates = peers
+            .into_iter()
+            .filter_map(|peer| {
+                let record = records.iter().find(|record| record.id == peer.id())?;
+                if states.get(&record.id) != Some(&ShardState::Online) {
+                    return None;
+                }
+
+                if record.chunks >= record.max_chunks {
+                    return None;
+                }
+
+                Some(Candidate {
+                    peer,
+                    capacity: u64::try_from(record.max_chunks).ok()?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Ties in score are broken by order, so keep it independent of how the shards were listed.
+        candidates.sort_by_key(|candidate| candidate.peer.id());
+
+        let draining = states
+            .into_iter()
+            .filter(|&(_, state)| state == ShardState::Draining)
+            .map(|(id, _)| id)
+            .collect();
+
+        Ok(Self {
+            candidates,
+            draining,
+            domains,
+            fills,
+        })
+    }
+
+    /// Number of distinct failure domains chunks can be placed in, which is the most shards a
+    /// chunk can be placed on.
+    pub fn domains(&self) -> usize {
+        self.candidates
+            .iter()
+            .map(|candidate| self.domain(candidate.peer.id()))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    fn domain(&self, shard_id: Uuid) -> Domain {
+        self.domains
+            .get(&shard_id)
+            .cloned()
+            .unwrap_or(Domain::Unlabeled(shard_id))
+    }
+
+    /// Whether the shards `a` and `b` are in the same failure domain.
+    pub fn same_domain(&self, a: Uuid, b: Uuid) -> bool {
+        self.domain(a) == self.domain(b)
+    }
+
+    /// Whether the shard `shard_id` is draining, so that everything on it should be moved off.
+    pub fn is_draining(&self, shard_id: Uuid) -> bool {
+        self.draining.contains(&shard_id)
+    }
+
+    /// The fraction of its capacity the shard `shard_id` has used, if it is recorded.
+    pub fn fill(&self, shard_id: Uuid) -> Option<f64> {
+        self.fills.get(&shard_id).copied()
+    }
+
+    /// Ranks every shard for the chunk `hash`, best first.
+    pub fn rank(&self, hash: Hash) -> Vec<&Peer> {
+        let mut scored = self
+            .candidates
+            .iter()
+            .map(|candidate| {
+                (
+                    score(hash, candidate.peer.id(), candidate.capacity),
+                    &candidate.peer,
+                )
+            })
+            .collect::<Vec<_>>();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        scored.into_iter().map(|(_, peer)| peer).collect()
+    }
+
+    /// Picks the `count` best shards for the chunk `hash`, each in a different failure domain
+    /// from the others and from every shard in `exclude`, which already hold the chunk.
+    ///
+    /// Returns fewer than `count` shards if there are not enough domains to pick from.
+    pub fn place(&self, hash: Hash, count: usize, exclude: &[Uuid]) -> Vec<&Peer> {
+        let mut used = exclude
+            .iter()
+            .map(|&shard_id| self.domain(shard_id))
+            .collect::<HashSet<_>>();
+
+        self.rank(hash)
+            .into_iter()
+            .filter(|peer| used.insert(self.domain(peer.id())))
+            .take(count)
+            .collect()
+    }
+}
+
+/// The weighted rendezvous score of the shard `shard_id` for the chunk `hash`.
+///
+/// With `h` uniform in (0, 1), `weight / -ln(h)` makes each shard's chance of scoring highest
+/// proportional to its weight.
+fn score(hash: Hash, shard_id: Uuid, weight: u64) -> f64 {
+    let mut key = [0u8; Hash::LEN + 16];
+    key[..Hash::LEN].copy_from_slice(hash.as_bytes());
+    key[Hash::LEN..].copy_from_slice(shard_id.as_bytes());
+
+    let digest = Hash::of(&key).into_bytes();
+    let bits = u64::from_be_bytes(digest[..8].try_into().unwrap());
+
+    // The top 53 bits fill an f64's mantissa exactly; offset by half a step to exclude 0 and 1.
+    let h = ((bits >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+
+    weight as f64 / -h.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes() -> impl Iterator<Item = Hash> {
+        (0u32..10_000).map(|i| Hash::of(&i.to_le_bytes()))
+    }
+
+    /// The best scoring of `shards`, given as ID and weight, for the chunk `hash`.
+    fn best(hash: Hash, shards: &[(Uuid, u64)]) -> Uuid {
+        shards
+            .iter()
+            .max_by(|a, b| score(hash, a.0, a.1).total_cmp(&score(hash, b.0, b.1)))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn scores_are_stable() {
+        let hash = Hash::of(b"chunk");
+        let shard_id = Uuid::from_u128(1);
+
+        assert_eq!(score(hash, shard_id, 100), score(hash, shard_id, 100));
+        assert_eq!(score(hash, shard_id, 200), 2.0 * score(hash, shard_id, 100));
+        assert_ne!(
+            score(hash, shard_id, 100),
+            score(hash, Uuid::from_u128(2), 100)
+        );
+    }
+
+    #[test]
+    fn adding_a_shard_only_moves_chunks_to_it() {
+        let mut shards = (1..=4)
+            .map(|i| (Uuid::from_u128(i), 100))
+            .collect::<Vec<_>>();
+        let before = hashes().map(|hash| best(hash, &shards)).collect::<Vec<_>>();
+
+        let added = Uuid::from_u128(5);
+        shards.push((added, 100));
+
+        let mut moved = 0;
+        for (hash, before) in hashes().zip(before) {
+            let after = best(hash, &shards);
+            if after != before {
+                assert_eq!(after, added);
+                moved += 1;
+            }
+        }
+
+        // The new shard takes about a fifth of the chunks.
+        assert!((1_700..2_300).contains(&moved), "{moved} chunks moved");
+    }
+
+    #[test]
+    fn chunks_are_spread_by_weight() {
+        let small = Uuid::from_u128(1);
+        let large = Uuid::from_u128(2);
+        let shards = [(small, 100), (large, 300)];
+
+        let on_large = hashes()
+            .filter(|&hash| best(hash, &shards) == large)
+            .count();
+
+        assert!(
+            (7_200..7_800).contains(&on_large),
+            "{on_large} chunks on large"
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! Rebalancing of stored chunks across shards, as shards join, leave or fill up.
+//!
+//! Each pass walks every referenced chunk, and computes where [`placement`](crate::placement)
+//! would put it now. A replica or piece on a shard that is no longer among its chunk's targets is
+//! migrated to a target that doesn't hold the chunk yet, if that target is sufficiently emptier:
+//! it is read from its old shard, stored on the new one and read back from it, and only once it
+//! reads back intact is its placement moved and the old copy deleted.
+//!
+//! Everything on a draining shard is migrated off it, however full its targets are.
+//!
+//! Migrations between the same two shards are carried out in batches, when both support
+//! batching: the copies are read in one request and stored in another, which the new shard
+//! commits at once. It checks every copy's hash before storing it, so the copies are only checked
+//! to be there afterwards, rather than read back.
+//!
+//! Migration is throttled to the configured rate, so that rebalancing doesn't starve uploads and
+//! downloads of bandwidth.
+
+use crate::{
+    cfg,
+    db_store::{ChunkPlacements, Release},
+    gc,
+    net::shards::{self, Peer},
+    placement::Cluster,
+};
+use anyhow::Result;
+use lib::{
+    net::{Capabilities, MAX_BATCH_LEN},
+    Hash,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+use tokio::{
+    sync::{Mutex, Notify},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+use uuid::Uuid;
+
+/// Number of chunks planned per database query.
+const BATCH_LEN: i64 = 256;
+
+static WAKE: Notify = Notify::const_new();
+static PROGRESS: Mutex<Progress> = Mutex::const_new(Progress::new());
+
+/// The progress of the current or last rebalancing pass.
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    running: bool,
+    started: Option<String>,
+    finished: Option<String>,
+
+    /// Number of referenced chunks when the pass started.
+    chunks: i64,
+
+    /// Number of chunks checked for replicas or pieces to migrate.
+    checked: u64,
+
+    /// Number of replicas or pieces migrated, and their total size.
+    migrated: u64,
+    migrated_bytes: u64,
+
+    /// Number of replicas or pieces that failed to migrate.
+    failed: u64,
+}
+
+impl Progress {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            started: None,
+            finished: None,
+            chunks: 0,
+            checked: 0,
+            migrated: 0,
+            migrated_bytes: 0,
+            failed: 0,
+        }
+    }
+}
+
+/// A replica or piece to migrate.
+#[derive(Debug)]
+struct Migration {
+    hash: Hash,
+    stored_hash: Hash,
+    from: Uuid,
+    to: Uuid,
+}
+
+/// Starts rebalancing periodically, and whenever [`wake`] is called.
+pub fn start() {
+    tokio::spawn(rebalance());
+}
+
+/// Starts a rebalancing pass soon, unless one is already running.
+pub fn wake() {
+    WAKE.notify_one();
+}
+
+/// Returns the progress of the current or last rebalancing pass.
+pub async fn progress() -> Progress {
+    PROGRESS.lock().await.clone()
+}
+
+async fn rebalance() {
+    let mut interval = interval(Duration::from_millis(cfg::get().rebalance.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = WAKE.notified() => {}
+        }
+
+        if let Err(err) = pass().await {
+            error!("Error rebalancing: {err:?}");
+        }
+
+        let mut progress = PROGRESS.lock().await;
+        progress.running = false;
+        progress.finished = Some(chrono::Utc::now().to_rfc3339());
+    }
+}
+
+async fn pass() -> Result<()> {
+    let db_store = crate::DB_STORE.read().await;
+    let chunks = db_store.get().unwrap().count_referenced_chunks().await?;
+    drop(db_store);
+
+    *PROGRESS.lock().await = Progress {
+        running: true,
+        started: Some(chrono::Utc::now().to_rfc3339()),
+        chunks,
+        ..Progress::new()
+    };
+
+    let rate = std::cmp::max(cfg::get().rebalance.rate, 1);
+    let mut next_at = Instant::now();
+
+    let mut after = None;
+    loop {
+        let db_store = crate::DB_STORE.read().await;
+        let batch = db_store
+            .get()
+            .unwrap()
+            .get_chunk_placements(after, BATCH_LEN)
+            .await?;
+        drop(db_store);
+
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = Some(last.hash);
+
+        // Take a new snapshot for every batch, since migrating changes how full shards are.
+        let cluster = Cluster::snapshot().await?;
+
+        // Group the migrations between the same two shards, so they can be batched.
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for chunk in &batch {
+            for migration in plan(&cluster, chunk)? {
+                groups
+                    .entry((migration.from, migration.to))
+                    .or_default()
+                    .push(migration);
+            }
+
+            PROGRESS.lock().await.checked += 1;
+        }
+
+        for ((from, to), migrations) in groups {
+            // What a disconnected shard holds can't be read or deleted, so it stays there.
+            let Some(from_shard) = shards::get(from).await else {
+                continue;
+            };
+            let to_shard = shards::get(to).await.filter(|to_shard| {
+                from_shard.supports(Capabilities::BATCHING)
+                    && to_shard.supports(Capabilities::BATCHING)
+            });
+
+            for migrations in migrations.chunks(MAX_BATCH_LEN) {
+                sleep_until(next_at).await;
+
+                let results = match &to_shard {
+                    Some(to_shard) => migrate_batch(migrations, &from_shard, to_shard).await,
+
+                    None => {
+                        let mut results = Vec::with_capacity(migrations.len());
+                        for migration in migrations {
+                            results.push(migrate(migration).await);
+                        }
+
+                        results
+                    }
+                };
+
+                let mut len = 0;
+                for (migration, result) in migrations.iter().zip(results) {
+                    len += record(migration, result).await;
+                }
+                next_at = Instant::now() + Duration::from_secs_f64(len as f64 / rate as f64);
+            }
+        }
+    }
+
+    let progress = PROGRESS.lock().await;
+    debug!(
+        "Rebalanced: migrated {} replicas or pieces ({} bytes), {} failed",
+        progress.migrated, progress.migrated_bytes, progress.failed
+    );
+
+    Ok(())
+}
+
+/// Works out which of `chunk`'s replicas or pieces to migrate, and where to.
+fn plan(cluster: &Cluster, chunk: &ChunkPlacements) -> Result<Vec<Migration>> {
+    let threshold = f64::from(cfg::get().rebalance.threshold) / 100.0;
+
+    let targets = cluster
+        .place(chunk.hash, chunk.shard_ids.len(), &[])
+        .into_iter()
+        .map(|peer| peer.id())
+        .collect::<Vec<_>>();
+
+    // The shards that hold the chunk once the migrations planned so far are done.
+    let mut holders = chunk.shard_ids.clone();
+    let mut migrations = Vec::new();
+
+    for (&from, stored_hash) in chunk.shard_ids.iter().zip(&chunk.stored_hashes) {
+        if targets.contains(&from) {
+            continue;
+        }
+
+        let draining = cluster.is_draining(from);
+        let from_fill = cluster.fill(from).unwrap_or(1.0);
+        let to = targets.iter().copied().find(|&to| {
+            !holders.contains(&to)
+                && (draining || from_fill - cluster.fill(to).unwrap_or(1.0) >= threshold)
+                && !holders
+                    .iter()
+                    .any(|&holder| holder != from && cluster.same_domain(holder, to))
+        });
+
+        let Some(to) = to else {
+            continue;
+        };
+
+        holders.retain(|&holder| holder != from);
+        holders.push(to);
+
+        migrations.push(Migration {
+            hash: chunk.hash,
+            stored_hash: Hash::try_from(stored_hash.as_slice())?,
+            from,
+            to,
+        });
+    }
+
+    Ok(migrations)
+}
+
+/// Counts the outcome of a migration towards the pass's progress. Returns the number of bytes
+/// migrated.
+async fn record(migration: &Migration, result: Result<usize>) -> usize {
+    match result {
+        Ok(len) => {
+            let mut progress = PROGRESS.lock().await;
+            progress.migrated += 1;
+            progress.migrated_bytes += len as u64;
+
+            len
+        }
+
+        Err(err) => {
+            warn!(
+                "Error migrating chunk {} from shard {} to shard {}: {err:?}",
+                migration.hash, migration.from, migration.to
+            );
+
+            PROGRESS.lock().await.failed += 1;
+
+            0
+        }
+    }
+}
+
+/// Copies a replica or piece to its new shard, verifies it there, moves its placement and
+/// deletes the old copy. Returns its length.
+async fn migrate(migration: &Migration) -> Result<usize> {
+    let Migration {
+        stored_hash,
+        from,
+        to,
+        ..
+    } = *migration;
+
+    let Some(from_shard) = shards::get(from).await else {
+        bail!("shard {from} is disconnected");
+    };
+    let Some(to_shard) = shards::get(to).await else {
+        bail!("shard {to} is disconnected");
+    };
+
+    let chunk = shards::fetch(stored_hash, &[from]).await?;
+
+    let len = chunk.len();
+    shards::begin_store(to, stored_hash).await?;
+    to_shard.store(chunk).await?;
+
+    // Read back, to be sure the new shard stored an intact copy.
+    shards::fetch(stored_hash, &[to]).await?;
+
+    finish(migration, &from_shard).await?;
+
+    Ok(len)
+}
+
+/// Migrates `migrations`, all between the same two shards, with batch requests. Returns the
+/// outcome of each.
+async fn migrate_batch(
+    migrations: &[Migration],
+    from_shard: &Peer,
+    to_shard: &Peer,
+) -> Vec<Result<usize>> {
+    let stored_hashes = migrations
+        .iter()
+        .map(|migration| migration.stored_hash)
+        .collect::<Vec<_>>();
+
+    let chunks = match from_shard.retrieve_batch(stored_hashes.clone()).await {
+        Ok(chunks) => chunks,
+        Err(err) => return batch_failed(migrations, &err),
+    };
+
+    // Only intact copies are stored on the new shard.
+    let mut results = Vec::with_capacity(migrations.len());
+    let mut copies = Vec::new();
+    for (migration, chunk) in migrations.iter().zip(chunks) {
+        match chunk {
+            Some(chunk) if Hash::of(&chunk) == migration.stored_hash => {
+                results.push(Ok(chunk.len()));
+                copies.push(chunk);
+            }
+
+            Some(_) => results.push(Err(anyhow!(
+                "shard {} holds a corrupt copy",
+                migration.from
+            ))),
+            None => results.push(Err(anyhow!("shard {} holds no copy", migration.from))),
+        }
+    }
+
+    if copies.is_empty() {
+        return results;
+    }
+    for copy in &copies {
+        if let Err(err) = shards::begin_store(to_shard.id(), copy.hash()).await {
+            return batch_failed(migrations, &err);
+        }
+    }
+    if let Err(err) = to_shard.store_batch(copies).await {
+        return batch_failed(migrations, &err);
+    }
+
+    let stored = match to_shard.exists_batch(stored_hashes).await {
+        Ok(stored) => stored,
+        Err(err) => return batch_failed(migrations, &err),
+    };
+
+    for ((migration, result), stored) in migrations.iter().zip(&mut results).zip(stored) {
+        let Ok(len) = *result else {
+            continue;
+        };
+
+        *result = if stored {
+            finish(migration, from_shard).await.map(|()| len)
+        } else {
+            Err(anyhow!("shard {} did not store a copy", migration.to))
+        };
+    }
+
+    results
+}
+
+fn batch_failed(migrations: &[Migration], err: &anyhow::Error) -> Vec<Result<usize>> {
+    migrations
+        .iter()
+        .map(|_| Err(anyhow!("batch failed: {err:?}")))
+        .collect()
+}
+
+/// Moves the placement of a replica or piece that has been copied to its new shard, and deletes
+/// the old copy.
+async fn finish(migration: &Migration, from_shard: &Peer) -> Result<()> {
+    let Migration { hash, from, to, .. } = *migration;
+
+    let db_store = crate::DB_STORE.read().await;
+    let release = db_store
+        .get()
+        .unwrap()
+        .move_placement(hash, from, to)
+        .await?;
+    drop(db_store);
+
+    match release {
+        Release::Delete(stored_hash) => gc::delete(from_shard, stored_hash).await?,
+        Release::Shared => {}
+
+        // The chunk was released meanwhile, so the new copy isn't needed after all; like any
+        // store that goes unrecorded, it is left for garbage collection to delete.
+        Release::Kept => bail!("chunk is no longer placed on shard {from}"),
+    }
+
+    trace!("Migrated chunk {hash} from shard {from} to shard {to}");
+
+    Ok(())
+}
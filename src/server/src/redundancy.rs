@@ -0,0 +1,340 @@
+//! How chunks are stored redundantly: as whole replicas on several shards, or erasure coded with
+//! Reed-Solomon into data and parity pieces, each on a different shard.
+//!
+//! An erasure coded chunk is split into `data` pieces of equal length (the last one padded with
+//! zeroes), from which `parity` more pieces are computed; any `data` of the pieces are enough to
+//! reconstruct the chunk. Shards store each piece as a chunk of its own, under its own hash.
+//!
+//! A chunk keeps the coding it was first stored with for as long as it is recorded, even when
+//! later uploads of it ask for another policy.
+
+use crate::{cfg, db_store::ChunkLocation, net::shards};
+use anyhow::Result;
+use axum::body::Bytes;
+use futures::future::join_all;
+use lib::Hash;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::Deserialize;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// The redundancy policy an upload asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    Replicated,
+    ErasureCoded,
+}
+
+impl Policy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Replicated => "replicated",
+            Self::ErasureCoded => "erasure_coded",
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replicated" => Ok(Self::Replicated),
+            "erasure_coded" => Ok(Self::ErasureCoded),
+            _ => bail!("unknown redundancy policy: {s:?}"),
+        }
+    }
+}
+
+/// How to store each chunk of an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redundancy {
+    Replicated { replicas: usize },
+    ErasureCoded(ErasureCoding),
+}
+
+impl Redundancy {
+    /// Resolves the redundancy an upload asks for, falling back to the cluster-wide settings.
+    pub fn resolve(policy: Option<Policy>, replicas: Option<usize>) -> Result<Self> {
+        match (policy.unwrap_or(Policy::Replicated), replicas) {
+            (Policy::Replicated, Some(0)) => bail!("chunks must be stored on at least one shard"),
+            (Policy::Replicated, replicas) => Ok(Self::Replicated {
+                replicas: replicas.unwrap_or(cfg::get().replication.factor),
+            }),
+
+            (Policy::ErasureCoded, Some(_)) => bail!("erasure coded chunks have no replicas"),
+            (Policy::ErasureCoded, None) => {
+                let erasure = &cfg::get().erasure;
+
+                Ok(Self::ErasureCoded(ErasureCoding::new(
+                    erasure.data,
+                    erasure.parity,
+                )?))
+            }
+        }
+    }
+
+    /// Number of distinct shards each chunk is stored on.
+    pub fn shards(&self) -> usize {
+        match *self {
+            Self::Replicated { replicas } => replicas,
+            Self::ErasureCoded(coding) => coding.pieces(),
+        }
+    }
+
+    /// Number of replicas that survive as many shard losses as this does.
+    ///
+    /// Chunks that are replicated regardless of the policy, such as packs and chunks first stored
+    /// by a replicated upload, are stored this many times.
+    pub fn replicas(&self) -> usize {
+        match *self {
+            Self::Replicated { replicas } => replicas,
+            Self::ErasureCoded(coding) => coding.parity + 1,
+        }
+    }
+
+    /// The coding of newly stored chunks, or `None` if they are replicated.
+    pub fn coding(&self) -> Option<ErasureCoding> {
+        match *self {
+            Self::Replicated { .. } => None,
+            Self::ErasureCoded(coding) => Some(coding),
+        }
+    }
+}
+
+/// Reed-Solomon coding of a chunk into `data` data pieces and `parity` parity pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureCoding {
+    pub data: usize,
+    pub parity: usize,
+}
+
+impl ErasureCoding {
+    pub fn new(data: usize, parity: usize) -> Result<Self> {
+        // Fail now, rather than on the first chunk stored.
+        ReedSolomon::new(data, parity)?;
+
+        Ok(Self { data, parity })
+    }
+
+    /// Reads the coding recorded for a chunk, which is `None` if the chunk is replicated.
+    pub fn from_columns(data: Option<i16>, parity: Option<i16>) -> Result<Option<Self>> {
+        match (data, parity) {
+            (Some(data), Some(parity)) => Ok(Some(Self::new(
+                usize::try_from(data)?,
+                usize::try_from(parity)?,
+            )?)),
+            (None, None) => Ok(None),
+            _ => bail!("chunk has only half of an erasure coding recorded"),
+        }
+    }
+
+    /// Total number of pieces.
+    pub fn pieces(&self) -> usize {
+        self.data + self.parity
+    }
+
+    /// Splits `chunk` into data pieces, followed by the parity pieces computed from them.
+    pub fn encode(&self, chunk: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let piece_len = std::cmp::max(chunk.len().div_ceil(self.data), 1);
+
+        let mut pieces = chunk
+            .chunks(piece_len)
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>();
+        pieces.resize(self.pieces(), Vec::new());
+        for piece in &mut pieces {
+            piece.resize(piece_len, 0);
+        }
+
+        ReedSolomon::new(self.data, self.parity)?.encode(&mut pieces)?;
+
+        Ok(pieces)
+    }
+
+    /// Reconstructs a chunk of `len` bytes from its pieces, at least `data` of which must be
+    /// present.
+    pub fn decode(&self, mut pieces: Vec<Option<Vec<u8>>>, len: usize) -> Result<Vec<u8>> {
+        if pieces[..self.data].iter().any(Option::is_none) {
+            ReedSolomon::new(self.data, self.parity)?.reconstruct_data(&mut pieces)?;
+        }
+
+        let mut chunk = pieces
+            .into_iter()
+            .take(self.data)
+            .flatten()
+            .flatten()
+            .collect::<Vec<_>>();
+        chunk.truncate(len);
+
+        Ok(chunk)
+    }
+}
+
+/// Fetches the whole chunk at `location`, reconstructing it from its pieces if it has no replica
+/// that can be read.
+pub async fn fetch(location: &ChunkLocation) -> Result<Bytes> {
+    let coding = ErasureCoding::from_columns(location.data_pieces, location.parity_pieces)?;
+
+    let Some(coding) = coding else {
+        let chunk = shards::fetch(location.hash, &location.shard_ids).await?;
+
+        return Ok(Bytes::copy_from_slice(&chunk));
+    };
+
+    if !location.shard_ids.is_empty() {
+        match shards::fetch(location.hash, &location.shard_ids).await {
+            Ok(chunk) => return Ok(Bytes::copy_from_slice(&chunk)),
+            Err(err) => debug!("Reconstructing chunk {}: {err:?}", location.hash),
+        }
+    }
+
+    // A piece may be placed on more than one shard, e.g. while it is being moved.
+    let mut placements = vec![Vec::new(); coding.pieces()];
+    for ((&shard_id, &piece), stored_hash) in location
+        .piece_shard_ids
+        .iter()
+        .zip(&location.pieces)
+        .zip(&location.piece_hashes)
+    {
+        if let Some(placements) = usize::try_from(piece)
+            .ok()
+            .and_then(|piece| placements.get_mut(piece))
+        {
+            placements.push((shard_id, Hash::try_from(stored_hash.as_slice())?));
+        }
+    }
+
+    // Data pieces need no decoding, so only read parity pieces in place of data pieces that
+    // could not be read.
+    let mut pieces = vec![None; coding.pieces()];
+    let mut read = 0;
+    let mut next = 0;
+    while read < coding.data && next < coding.pieces() {
+        let batch = next..std::cmp::min(next + coding.data - read, coding.pieces());
+        next = batch.end;
+
+        let results = join_all(batch.clone().map(|piece| fetch_piece(&placements[piece]))).await;
+        for (piece, result) in batch.zip(results) {
+            match result {
+                Ok(bytes) => {
+                    pieces[piece] = Some(bytes);
+                    read += 1;
+                }
+
+                Err(err) => debug!("Piece {piece} of chunk {}: {err:?}", location.hash),
+            }
+        }
+    }
+
+    if read < coding.data {
+        bail!(
+            "only {read} of the {} pieces needed to reconstruct chunk {} are available",
+            coding.data,
+            location.hash
+        );
+    }
+
+    let chunk = coding.decode(pieces, usize::try_from(location.chunk_length)?)?;
+    if Hash::of(&chunk) != location.hash {
+        bail!("chunk {} was reconstructed incorrectly", location.hash);
+    }
+
+    Ok(Bytes::from(chunk))
+}
+
+/// Fetches a piece from the first of its placements that can be read.
+async fn fetch_piece(placements: &[(Uuid, Hash)]) -> Result<Vec<u8>> {
+    for &(shard_id, stored_hash) in placements {
+        if let Ok(chunk) = shards::fetch(stored_hash, &[shard_id]).await {
+            return Ok(chunk.to_vec());
+        }
+    }
+
+    bail!("piece is not available from any shard")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn encodes_data_then_parity() {
+        let coding = ErasureCoding::new(4, 2).unwrap();
+        let chunk = chunk(1001);
+
+        let pieces = coding.encode(&chunk).unwrap();
+        assert_eq!(pieces.len(), 6);
+        assert!(pieces.iter().all(|piece| piece.len() == 251));
+        assert_eq!(pieces[..4].concat()[..chunk.len()], chunk);
+    }
+
+    #[test]
+    fn reconstructs_from_any_data_pieces() {
+        let coding = ErasureCoding::new(4, 2).unwrap();
+        let chunk = chunk(1001);
+        let pieces = coding.encode(&chunk).unwrap();
+
+        // Every way of losing up to `parity` pieces.
+        for lost_a in 0..6 {
+            for lost_b in lost_a..6 {
+                let pieces = pieces
+                    .iter()
+                    .enumerate()
+                    .map(|(i, piece)| (i != lost_a && i != lost_b).then(|| piece.clone()))
+                    .collect();
+
+                assert_eq!(coding.decode(pieces, chunk.len()).unwrap(), chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn fails_with_too_few_pieces() {
+        let coding = ErasureCoding::new(4, 2).unwrap();
+        let chunk = chunk(1001);
+
+        let mut pieces = coding
+            .encode(&chunk)
+            .unwrap()
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        pieces[0] = None;
+        pieces[2] = None;
+        pieces[5] = None;
+
+        assert!(coding.decode(pieces, chunk.len()).is_err());
+    }
+
+    #[test]
+    fn codes_chunks_shorter_than_the_pieces() {
+        let coding = ErasureCoding::new(4, 2).unwrap();
+
+        for len in [0, 1, 3] {
+            let chunk = chunk(len);
+            let mut pieces = coding
+                .encode(&chunk)
+                .unwrap()
+                .into_iter()
+                .map(Some)
+                .collect::<Vec<_>>();
+            pieces[0] = None;
+
+            assert_eq!(coding.decode(pieces, len).unwrap(), chunk);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_codings() {
+        assert!(ErasureCoding::new(0, 2).is_err());
+        assert!(ErasureCoding::new(4, 0).is_err());
+        assert!(ErasureCoding::from_columns(Some(4), None).is_err());
+        assert_eq!(ErasureCoding::from_columns(None, None).unwrap(), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Repair of chunks that have lost replicas or pieces.
+//!
+//! A shard that stays unseen (disconnected, or not answering pings) for longer than the repair
+//! timeout is considered lost, along with everything placed on it. Each pass finds the referenced
+//! chunks with fewer replicas or pieces on the remaining shards than they should have, and stores
+//! the missing ones on new shards: replicas are copied from a surviving replica, and pieces are
+//! computed again from the chunk, reconstructed from the surviving pieces.
+//!
+//! Placements on a shard that reports its copy of a chunk corrupt are dropped at once, and a pass
+//! is started to replace them.
+//!
+//! Chunks closest to being lost (those that can survive the fewest further shard losses) are
+//! repaired first. Placements on lost shards are kept, so a shard that eventually reconnects
+//! leaves its chunks over-replicated rather than lost, for rebalancing to sort out.
+
+use crate::{
+    cfg,
+    db_store::{Placement, UnderReplicatedChunk},
+    net::shards,
+    placement::Cluster,
+    redundancy::{self, ErasureCoding},
+};
+use anyhow::Result;
+use futures::future::join_all;
+use lib::Hash;
+use std::{collections::BTreeSet, time::Duration};
+use tokio::{
+    sync::Notify,
+    time::{interval, MissedTickBehavior},
+};
+use uuid::Uuid;
+
+/// Number of chunks repaired per database query.
+const BATCH_LEN: i64 = 64;
+
+static WAKE: Notify = Notify::const_new();
+
+/// Starts repairing under-replicated chunks periodically, and whenever [`wake`] is called.
+pub fn start() {
+    tokio::spawn(repair());
+}
+
+/// Starts a repair pass soon, unless one is already running.
+pub fn wake() {
+    WAKE.notify_one();
+}
+
+async fn repair() {
+    let mut interval = interval(Duration::from_millis(cfg::get().repair.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = WAKE.notified() => {}
+        }
+
+        if let Err(err) = pass().await {
+            error!("Error repairing chunks: {err:?}");
+        }
+    }
+}
+
+async fn pass() -> Result<()> {
+    let lost = lost_shards().await?;
+
+    let mut skip = Vec::new();
+    let mut repaired = 0;
+    loop {
+        let db_store = crate::DB_STORE.read().await;
+        let batch = db_store
+            .get()
+            .unwrap()
+            .get_under_replicated_chunks(&lost, &skip, BATCH_LEN)
+            .await?;
+        drop(db_store);
+
+        if batch.is_empty() {
+            break;
+        }
+
+        // Take a new snapshot for every batch, since repairing changes how full shards are.
+        let cluster = Cluster::snapshot().await?;
+
+        for chunk in &batch {
+            let hash = chunk.location.hash;
+
+            match repair_chunk(&cluster, chunk).await {
+                Ok(0) => skip.push(hash),
+                Ok(stored) => {
+                    repaired += 1;
+                    trace!("Repaired chunk {hash}: stored {stored} replicas or pieces");
+
+                    // A chunk that is still short of shards is retried in the next pass.
+                    skip.push(hash);
+                }
+
+                Err(err) => {
+                    warn!("Error repairing chunk {hash}: {err:?}");
+                    skip.push(hash);
+                }
+            }
+        }
+    }
+
+    if !skip.is_empty() {
+        debug!(
+            "Repaired {repaired} of {} under-replicated chunks ({} shards lost)",
+            skip.len(),
+            lost.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the recorded shards that have not been seen for longer than the repair timeout, and
+/// are not connected.
+async fn lost_shards() -> Result<Vec<Uuid>> {
+    let timeout = Duration::from_millis(cfg::get().repair.timeout);
+
+    let db_store = crate::DB_STORE.read().await;
+    let unseen = db_store.get().unwrap().get_unseen_shards(timeout).await?;
+    drop(db_store);
+
+    let mut lost = Vec::new();
+    for shard_id in unseen {
+        if shards::get(shard_id).await.is_none() {
+            lost.push(shard_id);
+        }
+    }
+
+    Ok(lost)
+}
+
+/// Stores the replicas or pieces `chunk` is missing on new shards, and records them. Returns how
+/// many were stored.
+async fn repair_chunk(cluster: &Cluster, chunk: &UnderReplicatedChunk) -> Result<usize> {
+    let location = &chunk.location;
+    let coding = ErasureCoding::from_columns(location.data_pieces, location.parity_pieces)?;
+
+    // The shards still holding the chunk, whose failure domains new copies must avoid.
+    let holders = location
+        .shard_ids
+        .iter()
+        .chain(&location.piece_shard_ids)
+        .copied()
+        .collect::<Vec<_>>();
+
+    let chunk_data = redundancy::fetch(location).await?;
+
+    let placements = match coding {
+        None => {
+            let replicas = usize::try_from(chunk.replicas.unwrap_or(1))?;
+            let missing = replicas.saturating_sub(location.shard_ids.len());
+
+            let targets = cluster.place(location.hash, missing, &holders);
+            let stored = join_all(targets.iter().map(|target| {
+                shards::store_all(std::slice::from_ref(target), location.hash, &chunk_data)
+            }))
+            .await;
+
+            targets
+                .iter()
+                .zip(stored)
+                .filter_map(|(target, stored)| match stored {
+                    Ok(()) => Some(Placement::replica(target.id(), location.hash)),
+                    Err(err) => {
+                        warn!("Error storing replica on shard {}: {err:?}", target.id());
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        }
+
+        Some(coding) => {
+            let present = location
+                .pieces
+                .iter()
+                .filter_map(|&piece| usize::try_from(piece).ok())
+                .collect::<BTreeSet<_>>();
+            let missing = (0..coding.pieces())
+                .filter(|piece| !present.contains(piece))
+                .collect::<Vec<_>>();
+
+            let pieces = coding.encode(&chunk_data)?;
+            let targets = cluster.place(location.hash, missing.len(), &holders);
+            let stored = join_all(targets.iter().zip(&missing).map(|(target, &piece)| {
+                shards::store_all(
+                    std::slice::from_ref(target),
+                    Hash::of(&pieces[piece]),
+                    &pieces[piece],
+                )
+            }))
+            .await;
+
+            targets
+                .iter()
+                .zip(&missing)
+                .zip(stored)
+                .filter_map(|((target, &piece), stored)| match stored {
+                    Ok(()) => Some(Placement {
+                        shard_id: target.id(),
+                        piece: Some(piece),
+                        stored_hash: Hash::of(&pieces[piece]),
+                    }),
+                    Err(err) => {
+                        warn!(
+                            "Error storing piece {piece} on shard {}: {err:?}",
+                            target.id()
+                        );
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+
+    if placements.is_empty() {
+        return Ok(0);
+    }
+
+    let db_store = crate::DB_STORE.read().await;
+    let recorded = db_store
+        .get()
+        .unwrap()
+        .add_placements(location.hash, &placements)
+        .await?;
+    drop(db_store);
+
+    // The chunk was released meanwhile, so the new copies aren't needed after all; like any
+    // store that goes unrecorded, they are left for garbage collection to delete.
+    if !recorded {
+        bail!("chunk is no longer referenced");
+    }
+
+    Ok(placements.len())
+}
@@ -1,22 +1,67 @@
+use anyhow::{bail, Result};
+use lib::{net::MAX_LABEL_LEN, noise::PublicKey, FailureDomain};
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct Cfg {
-    tls: bool,
+    tls: Tls,
     remote: String,
     storage: Storage,
-    message_timeout: u64,
+    timeout: Timeout,
+    workers: usize,
+    scrub: Scrub,
+
+    #[serde(default)]
+    server: Server,
+
+    #[serde(default)]
+    enrollment: Enrollment,
+
+    /// Labels of the failure domain the shard is in, which are all optional.
+    #[serde(default)]
+    domain: FailureDomain,
 }
 
 impl Cfg {
+    /// Checks the values that deserializing alone can't, so that a bad one is reported on start
+    /// rather than where it is first used.
+    fn check(&self) -> Result<()> {
+        if self.server.key.parse::<PublicKey>().is_err() {
+            bail!(
+                "DIMESE_SHARD_SERVER_KEY must be set to the server's public key in hex; the server \
+                 logs it on start, and returns it from `GET /api/admin/enrollment`"
+            );
+        }
+
+        let labels = [
+            ("ZONE", &self.domain.zone),
+            ("RACK", &self.domain.rack),
+            ("HOST", &self.domain.host),
+        ];
+        for (name, label) in labels {
+            if label
+                .as_ref()
+                .is_some_and(|label| label.len() > MAX_LABEL_LEN)
+            {
+                bail!("DIMESE_SHARD_DOMAIN_{name} is longer than {MAX_LABEL_LEN} bytes");
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn remote(&self) -> &str {
         self.remote.as_str()
     }
 
     pub fn use_tls(&self) -> bool {
-        self.tls
+        self.tls.enabled
+    }
+
+    pub fn tls(&self) -> &Tls {
+        &self.tls
     }
 
     pub fn storage(&self) -> &Storage {
@@ -24,7 +69,55 @@ impl Cfg {
     }
 
     pub fn message_timeout(&self) -> Duration {
-        Duration::from_millis(self.message_timeout)
+        Duration::from_millis(self.timeout.message)
+    }
+
+    /// Number of requests from the server handled at once.
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn domain(&self) -> &FailureDomain {
+        &self.domain
+    }
+
+    pub fn scrub(&self) -> &Scrub {
+        &self.scrub
+    }
+
+    /// The static key the server must present, or the shard refuses to talk to it.
+    pub fn server_key(&self) -> PublicKey {
+        self.server
+            .key
+            .parse()
+            .expect("server key was checked on load")
+    }
+
+    /// The one-time token to enroll with, for a shard the server doesn't know yet.
+    pub fn enrollment_token(&self) -> Option<&str> {
+        self.enrollment.token.as_deref()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    enabled: bool,
+    ca: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+}
+
+impl Tls {
+    /// Path to a PEM bundle of the CA certificates to trust the server's certificate from, in
+    /// place of the public web roots.
+    pub fn ca(&self) -> Option<&str> {
+        self.ca.as_deref()
+    }
+
+    /// Paths to the PEM certificate chain and private key to present to the server, if both are
+    /// set.
+    pub fn client_cert(&self) -> Option<(&str, &str)> {
+        self.cert.as_deref().zip(self.key.as_deref())
     }
 }
 
@@ -44,11 +137,44 @@ impl Storage {
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct Timeout {
+    message: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Scrub {
+    rate: u64,
+    interval: u64,
+}
+
+impl Scrub {
+    /// Bytes per second of stored chunks to verify, at most.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Time to wait after verifying every stored chunk before starting over.
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Server {
+    key: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Enrollment {
+    token: Option<String>,
+}
+
 pub fn get() -> &'static Cfg {
     static APP_CONFIG: Lazy<Cfg> = Lazy::new(|| {
         use config::{Config, Environment};
 
-        Config::builder()
+        let cfg: Cfg = Config::builder()
             .add_source(
                 Environment::with_prefix("DIMESE_SHARD")
                     .separator("_")
@@ -56,7 +182,10 @@ pub fn get() -> &'static Cfg {
             )
             .build()
             .and_then(Config::try_deserialize)
-            .expect("failed to read configuration")
+            .expect("failed to read configuration");
+        cfg.check().expect("invalid configuration");
+
+        cfg
     });
 
     &APP_CONFIG
@@ -1,5 +1,6 @@
 mod cfg;
 mod net;
+mod scrub;
 mod storage;
 
 #[macro_use]
@@ -38,10 +39,18 @@ async fn main() {
 
     trace!("Initializing info...");
     info::init().expect("failed to initialize info");
-    debug!("Shard ID: {}", info::get_id());
+    match info::get_id() {
+        Some(id) => debug!("Shard ID: {id}"),
+        None => debug!("Shard ID: none yet"),
+    }
     debug!("Started: {}", info::get_started_at());
+    info!("Shard key: {}", info::get_keypair().public());
 
-    net::connect().await;
+    scrub::start();
+
+    if let Err(err) = net::connect().await {
+        error!("Error connecting to server: {err:?}");
+    }
 
     info!("Reached a safe shutdown point.");
 }
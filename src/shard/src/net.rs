@@ -1,7 +1,13 @@
-use anyhow::Result;
+use crate::storage::{chunk, info};
+use anyhow::{bail, Result};
 use lib::{
     bstr::BStr,
-    net::{Connection, Message},
+    chunk::Chunk,
+    net::{
+        self, Capabilities, Connection, Labels, Message, RecvHalf, RequestError, RequestId,
+        SendHalf,
+    },
+    noise, Hash,
 };
 use once_cell::sync::Lazy;
 use std::{
@@ -12,10 +18,15 @@ use std::{
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
+    sync::{mpsc, Mutex},
+    task::JoinSet,
     time::timeout,
 };
 use tokio_rustls::{
-    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
     TlsConnector,
 };
 
@@ -31,68 +42,345 @@ pub async fn connect() -> Result<()> {
     let stream = timeout(Duration::from_secs(5), TcpStream::connect(&*addrs)).await??;
 
     if cfg::get().use_tls() {
-        let mut root_cert_store = RootCertStore::empty();
-        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let tls_connector = TlsConnector::from(Arc::new(tls_config()?));
 
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth();
-        let tls_connector = TlsConnector::from(Arc::new(config));
-        let dns_name = ServerName::try_from(cfg::get().remote()).expect("not a valid remote host");
+        // The remote includes a port, which isn't part of the name the certificate is for.
+        let remote = cfg::get().remote();
+        let host = remote.rsplit_once(':').map_or(remote, |(host, _)| host);
+        let dns_name = ServerName::try_from(host.to_string()).expect("not a valid remote host");
 
         let stream = tls_connector
             .connect(dns_name, stream)
             .await
             .expect("TLS connect did not succeed");
 
-        listen(Connection::new(stream)).await
+        listen(stream).await
     } else {
-        listen(Connection::new(stream)).await
+        listen(stream).await
     }
 }
 
-async fn listen<IO: AsyncRead + AsyncWrite + Unpin>(mut connection: Connection<IO>) -> Result<()> {
-    static TIMEOUT: Lazy<Duration> = Lazy::new(|| cfg::get().message_timeout());
+/// Builds the TLS configuration: trusting the configured CA bundle, or else the public web roots,
+/// and presenting the configured client certificate, if any.
+fn tls_config() -> Result<ClientConfig> {
+    let tls = cfg::get().tls();
 
-    if let Message::AssignId { id } = recv_timeout(&mut connection, *TIMEOUT).await? {
-        connection.send(Message::Ok, true).await?;
-        todo!("set ID")
+    let mut root_cert_store = RootCertStore::empty();
+    match tls.ca() {
+        Some(ca) => {
+            for cert in CertificateDer::pem_file_iter(ca)? {
+                root_cert_store.add(cert?)?;
+            }
+        }
+        None => root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
     }
 
-    if let Message::ServerInfo { agent } = recv_timeout(&mut connection, *TIMEOUT).await? {
-        send_timeout(&mut connection, Message::Ok, true, *TIMEOUT).await?;
-        todo!("set server info")
-    }
+    let builder = ClientConfig::builder().with_root_certificates(root_cert_store);
+    let config = match tls.client_cert() {
+        Some((cert, key)) => {
+            let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+            let key = PrivateKeyDer::from_pem_file(key)?;
+
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(config)
+}
+
+async fn listen<IO: AsyncRead + AsyncWrite + Unpin>(mut stream: IO) -> Result<()> {
+    static TIMEOUT: Lazy<Duration> = Lazy::new(|| cfg::get().message_timeout());
+
+    // Authenticate the server before anything else is sent, and the shard to it.
+    let keypair = info::get_keypair();
+    let token = cfg::get().enrollment_token().unwrap_or_default();
+    let handshake = noise::initiate(
+        &mut stream,
+        &keypair,
+        cfg::get().server_key(),
+        token.as_bytes(),
+    );
+    let session = timeout(*TIMEOUT, handshake).await??;
+
+    let mut connection = Connection::new(stream, session.keys);
+    hello(&mut connection, *TIMEOUT).await?;
+    identify(&mut connection, *TIMEOUT).await?;
+
+    let server_agent = match recv_timeout(&mut connection, *TIMEOUT).await? {
+        Message::ServerInfo { agent } => agent,
+        message => bail!("Unexpected message (expected Message::ServerInfo): {message:?}"),
+    };
+    send_timeout(&mut connection, Message::Ok, true, *TIMEOUT).await?;
+    info!("Connected to server: {}", &*server_agent);
 
     let shard_info = Message::ShardInfo {
         chunks: cfg::get().storage().chunks(),
         agent: BStr::new(crate::agent_str()),
+        labels: Labels::new(cfg::get().domain()),
     };
     send_timeout(&mut connection, shard_info, true, *TIMEOUT).await?;
 
+    let (reader, writer) = connection.split();
+    let workers = std::cmp::max(cfg::get().workers(), 1);
+    let (requests_tx, requests_rx) = mpsc::channel(workers);
+    let (responses_tx, responses_rx) = mpsc::channel(workers);
+
+    // Workers are aborted along with the pool once the connection closes.
+    let requests_rx = Arc::new(Mutex::new(requests_rx));
+    let mut pool = JoinSet::new();
+    for _ in 0..workers {
+        pool.spawn(work(requests_rx.clone(), responses_tx.clone()));
+    }
+
+    tokio::select! {
+        result = receive_requests(reader, requests_tx) => result,
+        result = send_responses(writer, responses_rx) => result,
+    }
+}
+
+/// A request received from the server, or the response to one, with the ID of the request.
+type Frame = (RequestId, Message);
+
+async fn receive_requests<R: AsyncRead + Unpin>(
+    mut reader: RecvHalf<R>,
+    requests: mpsc::Sender<Frame>,
+) -> Result<()> {
     loop {
-        match connection.recv().await? {
-            Message::Ok => {}
+        let frame = reader.recv().await?;
 
-            Message::Ping => {
-                send_timeout(&mut connection, Message::Pong, true, *TIMEOUT).await?;
-            }
+        if requests.send(frame).await.is_err() {
+            bail!("Every worker has stopped");
+        }
+    }
+}
 
-            Message::Pong => {
-                debug!("Server sent unexpected pong. Ignoring.");
-            }
+async fn send_responses<W: AsyncWrite + Unpin>(
+    mut writer: SendHalf<W>,
+    mut responses: mpsc::Receiver<Frame>,
+) -> Result<()> {
+    let message_timeout = cfg::get().message_timeout();
 
-            Message::ShardStore { chunk } => todo!(),
-            Message::ShardRetrieve { id } => todo!(),
-            Message::ShardChunkExists { id } => todo!(),
+    while let Some((id, response)) = responses.recv().await {
+        // Only flush once no more responses are ready, so that those ready together go out
+        // together.
+        let flush = responses.is_empty();
+        timeout(message_timeout, writer.send(id, response, flush)).await??;
+    }
 
-            message => {
-                error!("Unexpected message (ignoring): {:?}", message);
-            }
+    Ok(())
+}
+
+/// Handles requests one at a time, as one of the pool of workers that share `requests`.
+async fn work(requests: Arc<Mutex<mpsc::Receiver<Frame>>>, responses: mpsc::Sender<Frame>) {
+    loop {
+        let Some((id, message)) = requests.lock().await.recv().await else {
+            break;
+        };
+
+        let Some(response) = handle(message).await else {
+            continue;
+        };
+
+        if responses.send((id, response)).await.is_err() {
+            break;
         }
     }
 }
 
+/// Carries out a request from the server, and returns the response to send it, if any.
+async fn handle(message: Message) -> Option<Message> {
+    let response = match message {
+        Message::Ok => return None,
+
+        Message::Ping => Ok(Message::Pong),
+
+        Message::Pong => {
+            debug!("Server sent unexpected pong. Ignoring.");
+            return None;
+        }
+
+        Message::ShardStore { chunk } => blocking(move || store(chunk)).await,
+        Message::ShardRetrieve { hash } => retrieve(hash).await,
+        Message::ShardChunkExists { hash } => blocking(move || exists(hash)).await,
+        Message::ShardDelete { hash } => blocking(move || delete(hash)).await,
+
+        Message::ShardStoreBatch { chunks } => blocking(move || store_batch(chunks)).await,
+        Message::ShardRetrieveBatch { hashes } => retrieve_batch(hashes).await,
+        Message::ShardChunkExistsBatch { hashes } => blocking(move || exists_batch(hashes)).await,
+
+        Message::ShardListCorrupt => Ok(Message::ShardCorrupt {
+            hashes: crate::scrub::corrupt(),
+        }),
+        Message::ShardCorruptHandled { hashes } => {
+            crate::scrub::forget_corrupt(&hashes);
+            Ok(Message::Ok)
+        }
+
+        // Every request must be answered, so that the server isn't left waiting for it.
+        message => {
+            error!("Unexpected message: {:?}", message);
+            Err(RequestError::Unexpected)
+        }
+    };
+
+    Some(response.unwrap_or_else(|error| Message::Error { error }))
+}
+
+/// Offers the server the protocol versions and capabilities the shard supports, and learns which
+/// the server picked.
+async fn hello<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<IO>,
+    timeout: Duration,
+) -> Result<()> {
+    let (min_version, max_version) = (net::MIN_PROTOCOL_VERSION, net::MAX_PROTOCOL_VERSION);
+    let hello = Message::Hello {
+        min_version,
+        max_version,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    tokio::time::timeout(timeout, connection.send_hello(hello)).await??;
+
+    match tokio::time::timeout(timeout, connection.recv_hello()).await?? {
+        Message::Hello {
+            min_version: version,
+            max_version: chosen,
+            capabilities,
+        } if version == chosen
+            && (min_version..=max_version).contains(&version)
+            && Capabilities::SUPPORTED.contains(capabilities) =>
+        {
+            info!("Speaking protocol version {version}, with capabilities {capabilities:?}");
+        }
+
+        Message::Error { error } => bail!(
+            "Server refused protocol versions {min_version}..={max_version}: {error}. The server \
+             and shard must be upgraded to overlapping versions."
+        ),
+        message => bail!("Unexpected response to Message::Hello: {message:?}"),
+    }
+
+    Ok(())
+}
+
+/// Presents the shard's ID to the server, or registers it with the server to be assigned one.
+async fn identify<IO: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<IO>,
+    timeout: Duration,
+) -> Result<()> {
+    let id = info::get_id();
+    send_timeout(connection, Message::ShardIdentify { id }, true, timeout).await?;
+
+    match recv_timeout(connection, timeout).await? {
+        Message::Ok if id.is_some() => {}
+
+        Message::AssignId { id: assigned } if id.is_none() => {
+            info::set_id(assigned)?;
+            send_timeout(connection, Message::Ok, true, timeout).await?;
+
+            info!("Registered with server as shard {assigned}");
+        }
+
+        Message::Error { error } => bail!("Server refused shard (ID {id:?}): {error}"),
+        message => bail!("Unexpected response to Message::ShardIdentify: {message:?}"),
+    }
+
+    Ok(())
+}
+
+/// Runs `request`, which reads or writes storage synchronously, on a blocking thread, so that it
+/// holds up neither the other workers nor the connection.
+async fn blocking(
+    request: impl FnOnce() -> Result<Message, RequestError> + Send + 'static,
+) -> Result<Message, RequestError> {
+    tokio::task::spawn_blocking(request)
+        .await
+        .expect("storage task panicked")
+}
+
+fn store(chunk: Chunk) -> Result<Message, RequestError> {
+    let hash = chunk.hash();
+    if Hash::of(&chunk) != hash {
+        return Err(RequestError::HashMismatch);
+    }
+
+    // Checked in the same transaction as the chunk is stored in, so that concurrent stores can't
+    // all pass the check and overfill the shard.
+    let capacity = cfg::get().storage().chunks();
+    if !chunk::put_chunks(std::slice::from_ref(&chunk), capacity).map_err(storage_error)? {
+        return Err(RequestError::Full);
+    }
+    trace!("Stored chunk {hash}");
+
+    Ok(Message::Ok)
+}
+
+/// Stores a batch of chunks in a single transaction, rather than committing each one.
+fn store_batch(chunks: Vec<Chunk>) -> Result<Message, RequestError> {
+    if chunks.len() > net::MAX_BATCH_LEN {
+        return Err(RequestError::BatchTooLong);
+    }
+    if chunks.iter().any(|chunk| Hash::of(chunk) != chunk.hash()) {
+        return Err(RequestError::HashMismatch);
+    }
+
+    if !chunk::put_chunks(&chunks, cfg::get().storage().chunks()).map_err(storage_error)? {
+        return Err(RequestError::Full);
+    }
+    trace!("Stored a batch of {} chunks", chunks.len());
+
+    Ok(Message::Ok)
+}
+
+async fn retrieve(hash: Hash) -> Result<Message, RequestError> {
+    match chunk::get_chunk(hash).await.map_err(storage_error)? {
+        Some(chunk) => Ok(Message::ShardChunk { chunk }),
+        None => Err(RequestError::NotFound),
+    }
+}
+
+async fn retrieve_batch(hashes: Vec<Hash>) -> Result<Message, RequestError> {
+    if hashes.len() > net::MAX_BATCH_LEN {
+        return Err(RequestError::BatchTooLong);
+    }
+
+    let chunks = chunk::get_chunks(&hashes).await.map_err(storage_error)?;
+
+    Ok(Message::ShardChunkBatch { chunks })
+}
+
+fn exists_batch(hashes: Vec<Hash>) -> Result<Message, RequestError> {
+    if hashes.len() > net::MAX_BATCH_LEN {
+        return Err(RequestError::BatchTooLong);
+    }
+
+    let exists = chunk::chunks_exist(&hashes).map_err(storage_error)?;
+
+    Ok(Message::ShardExistsBatch {
+        bitmap: net::bitmap(exists),
+    })
+}
+
+fn exists(hash: Hash) -> Result<Message, RequestError> {
+    let exists = chunk::chunk_exists(hash).map_err(storage_error)?;
+
+    Ok(Message::ShardExists { exists })
+}
+
+fn delete(hash: Hash) -> Result<Message, RequestError> {
+    if chunk::delete_chunk(hash).map_err(storage_error)? {
+        trace!("Deleted chunk {hash}");
+    }
+
+    Ok(Message::Ok)
+}
+
+fn storage_error(err: anyhow::Error) -> RequestError {
+    error!("Storage error: {err:?}");
+
+    RequestError::Storage
+}
+
 async fn send_timeout<IO: AsyncRead + AsyncWrite + Unpin>(
     connection: &mut Connection<IO>,
     message: Message,
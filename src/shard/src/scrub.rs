@@ -0,0 +1,75 @@
+//! Scrubbing of stored chunks: every chunk is periodically read back and rehashed, so that
+//! corrupted contents are found before they are needed.
+//!
+//! Chunks whose contents no longer match their hash are reported to the server whenever it asks,
+//! until it acknowledges having dealt with them, so that it can store them again from healthy
+//! copies elsewhere.
+
+use crate::{cfg, storage::chunk};
+use lib::Hash;
+use std::{collections::BTreeSet, sync::Mutex, time::Duration};
+use tokio::time::{sleep, sleep_until, Instant};
+
+static CORRUPT: Mutex<BTreeSet<Hash>> = Mutex::new(BTreeSet::new());
+
+/// Starts scrubbing stored chunks in the background.
+pub fn start() {
+    tokio::spawn(scrub());
+}
+
+/// Returns the chunks found corrupt that the server has not yet dealt with.
+pub fn corrupt() -> Vec<Hash> {
+    CORRUPT.lock().unwrap().iter().copied().collect()
+}
+
+/// Stops reporting the corrupt chunks `hashes`, once the server has dealt with them.
+pub fn forget_corrupt(hashes: &[Hash]) {
+    let mut corrupt = CORRUPT.lock().unwrap();
+
+    for hash in hashes {
+        corrupt.remove(hash);
+    }
+}
+
+async fn scrub() {
+    let rate = std::cmp::max(cfg::get().scrub().rate(), 1);
+
+    loop {
+        let mut after = None;
+        let mut next_at = Instant::now();
+        let (mut checked, mut corrupt) = (0u64, 0u64);
+
+        loop {
+            sleep_until(next_at).await;
+
+            let verified = tokio::task::spawn_blocking(move || chunk::verify_next_chunk(after))
+                .await
+                .expect("scrubbing task panicked");
+
+            let verified = match verified {
+                Ok(Some(verified)) => verified,
+                Ok(None) => break,
+
+                Err(err) => {
+                    error!("Error scrubbing chunks: {err:?}");
+                    break;
+                }
+            };
+
+            after = Some(verified.hash);
+            next_at = Instant::now() + Duration::from_secs_f64(verified.len as f64 / rate as f64);
+            checked += 1;
+
+            if !verified.intact {
+                warn!("Chunk {} is corrupt", verified.hash);
+
+                CORRUPT.lock().unwrap().insert(verified.hash);
+                corrupt += 1;
+            }
+        }
+
+        debug!("Scrubbed {checked} chunks, {corrupt} corrupt");
+
+        sleep(cfg::get().scrub().interval()).await;
+    }
+}
@@ -1,44 +1,333 @@
 use super::{get_db, Result};
-use lib::chunk::Chunk;
-use redb::TableDefinition;
+use anyhow::bail;
+use lib::{chunk::Chunk, Hash};
+use redb::{
+    ReadableTable, ReadableTableMetadata, TableDefinition, TableError, TableHandle,
+    WriteTransaction,
+};
+use sha2::{Digest, Sha256};
+use std::ops::Bound;
 use uuid::Uuid;
 
-pub(super) static TABLE_DEF: TableDefinition<[u8; size_of::<Uuid>()], &[u8; Chunk::SIZE]> =
+pub(super) static TABLE_DEF: TableDefinition<[u8; Hash::LEN], &[u8]> =
+    TableDefinition::new("chunk_data");
+
+/// The table chunks were stored in, by hash, when they were all padded to `Chunk::SIZE` bytes.
+static LEGACY_TABLE_DEF: TableDefinition<[u8; Hash::LEN], &[u8; Chunk::SIZE]> =
+    TableDefinition::new("chunks");
+
+/// The same table from before chunks were identified by their hash, when it was keyed by ID.
+static LEGACY_ID_TABLE_DEF: TableDefinition<[u8; size_of::<Uuid>()], &[u8; Chunk::SIZE]> =
     TableDefinition::new("chunks");
 
-pub fn chunk_exists(id: Uuid) -> Result<bool> {
+/// Moves the chunks in the legacy table over to [`TABLE_DEF`] at their real length, then drops it.
+///
+/// Chunks stored by ID can't be moved, since nothing records which hash each ID stood for; if
+/// there are any, this fails rather than lose them, and the operator must deal with them first.
+pub(super) fn migrate_legacy(write_txn: &WriteTransaction) -> Result<()> {
+    let Some(legacy_tbl_handle) = write_txn
+        .list_tables()?
+        .find(|table| table.name() == LEGACY_TABLE_DEF.name())
+    else {
+        return Ok(());
+    };
+
+    match write_txn.open_table(LEGACY_TABLE_DEF) {
+        Ok(legacy_tbl) => {
+            let mut chunk_tbl = write_txn.open_table(TABLE_DEF)?;
+
+            for entry in legacy_tbl.iter()? {
+                let (hash, data) = entry?;
+                let (hash, data) = (hash.value(), data.value());
+
+                let len = unpadded_len(Hash::from_bytes(hash), data).unwrap_or_else(|| {
+                    // Kept whole, for the scrubber to find and report.
+                    warn!("Legacy chunk {} is corrupt", Hash::from_bytes(hash));
+                    data.len()
+                });
+                chunk_tbl.insert(hash, &data[..len])?;
+            }
+
+            info!(
+                "Migrated {} chunks out of the legacy chunk table",
+                legacy_tbl.len()?
+            );
+        }
+
+        Err(TableError::TableTypeMismatch { .. }) => {
+            let count = write_txn.open_table(LEGACY_ID_TABLE_DEF)?.len()?;
+            if count > 0 {
+                bail!(
+                    "the legacy chunk table holds {count} chunks stored by ID, which can't be \
+                     migrated; move them off this shard or delete the table"
+                );
+            }
+        }
+
+        Err(err) => return Err(err.into()),
+    }
+
+    write_txn.delete_table(legacy_tbl_handle)?;
+
+    Ok(())
+}
+
+/// Finds how long a chunk padded with zeros to `Chunk::SIZE` bytes really is: the length of the
+/// prefix of `padded` that hashes to `hash`, if there is one.
+fn unpadded_len(hash: Hash, padded: &[u8]) -> Option<usize> {
+    // The chunk may itself end in zeros, so try every length from its last non-zero byte on.
+    let min_len = padded
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+
+    let mut hasher = Sha256::new_with_prefix(&padded[..min_len]);
+    for len in min_len..=padded.len() {
+        if <[u8; Hash::LEN]>::from(hasher.clone().finalize()) == hash.into_bytes() {
+            return Some(len);
+        }
+        hasher.update([0]);
+    }
+
+    None
+}
+
+pub fn chunk_exists(hash: Hash) -> Result<bool> {
     Ok(get_db()
         .begin_read()?
         .open_table(TABLE_DEF)?
-        .get(id.to_bytes_le())?
+        .get(hash.into_bytes())?
         .is_some())
 }
 
-pub async fn get_chunk(id: Uuid) -> Result<Option<Chunk>> {
-    let id_bytes = id.to_bytes_le();
+/// Returns whether each of the chunks `hashes` is stored, in the same order.
+pub fn chunks_exist(hashes: &[Hash]) -> Result<Vec<bool>> {
+    let read_txn = get_db().begin_read()?;
+    let chunk_tbl = read_txn.open_table(TABLE_DEF)?;
+
+    hashes
+        .iter()
+        .map(|hash| Ok(chunk_tbl.get(hash.into_bytes())?.is_some()))
+        .collect()
+}
+
+/// Reads a chunk on a blocking thread, so as not to hold up the caller's runtime.
+pub async fn get_chunk(hash: Hash) -> Result<Option<Chunk>> {
+    let data = tokio::task::spawn_blocking(move || read_chunks(&[hash])).await??;
+
+    match data.into_iter().next().flatten() {
+        Some(data) => Ok(Some(new_chunk(hash, &data).await)),
+        None => Ok(None),
+    }
+}
 
+/// Returns each of the chunks `hashes` that is stored, in the same order, all read in one
+/// transaction on a blocking thread.
+pub async fn get_chunks(hashes: &[Hash]) -> Result<Vec<Option<Chunk>>> {
+    let read = hashes.to_vec();
+    let data = tokio::task::spawn_blocking(move || read_chunks(&read)).await??;
+
+    let mut chunks = Vec::with_capacity(hashes.len());
+    for (&hash, data) in hashes.iter().zip(data) {
+        let chunk = match data {
+            Some(data) => Some(new_chunk(hash, &data).await),
+            None => None,
+        };
+
+        chunks.push(chunk);
+    }
+
+    Ok(chunks)
+}
+
+fn read_chunks(hashes: &[Hash]) -> Result<Vec<Option<Vec<u8>>>> {
     let read_txn = get_db().begin_read()?;
     let chunk_tbl = read_txn.open_table(TABLE_DEF)?;
 
-    match chunk_tbl.get(id_bytes)? {
-        Some(data) => {
-            let mut chunk = Chunk::new_zeroed(id).await;
-            chunk.copy_from_slice(data.value());
+    hashes
+        .iter()
+        .map(|hash| {
+            Ok(chunk_tbl
+                .get(hash.into_bytes())?
+                .map(|data| data.value().to_vec()))
+        })
+        .collect()
+}
+
+async fn new_chunk(hash: Hash, data: &[u8]) -> Chunk {
+    let mut chunk = Chunk::new_zeroed(hash, data.len()).await;
+    chunk.copy_from_slice(data);
 
-            Ok(Some(chunk))
+    chunk
+}
+
+/// A stored chunk, as checked by [`verify_next_chunk`].
+#[derive(Debug, Clone, Copy)]
+pub struct Verified {
+    pub hash: Hash,
+    pub len: usize,
+
+    /// Whether the chunk's contents still hash to its hash.
+    pub intact: bool,
+}
+
+/// Rehashes the first chunk stored after `after` in hash order (or the very first chunk, if
+/// `after` is `None`). Returns `None` once there are no more chunks.
+pub fn verify_next_chunk(after: Option<Hash>) -> Result<Option<Verified>> {
+    let read_txn = get_db().begin_read()?;
+    let chunk_tbl = read_txn.open_table(TABLE_DEF)?;
+
+    let start = match after {
+        Some(after) => Bound::Excluded(after.into_bytes()),
+        None => Bound::Unbounded,
+    };
+
+    let Some((key, data)) = chunk_tbl
+        .range::<[u8; Hash::LEN]>((start, Bound::Unbounded))?
+        .next()
+        .transpose()?
+    else {
+        return Ok(None);
+    };
+
+    let hash = Hash::from_bytes(key.value());
+    let data = data.value();
+
+    Ok(Some(Verified {
+        hash,
+        len: data.len(),
+        intact: Hash::of(data) == hash,
+    }))
+}
+
+/// Stores every one of `chunks` in a single transaction, unless that would leave more than
+/// `capacity` chunks stored, in which case none are stored. Returns whether they were.
+pub fn put_chunks(chunks: &[Chunk], capacity: u64) -> Result<bool> {
+    let write_txn = get_db().begin_write()?;
+
+    let fits = {
+        let mut chunk_tbl = write_txn.open_table(TABLE_DEF)?;
+
+        // Storing a chunk again takes no more room.
+        let mut count = chunk_tbl.len()?;
+        for chunk in chunks {
+            if chunk_tbl.get(chunk.hash().into_bytes())?.is_none() {
+                count += 1;
+            }
         }
-        None => Ok(None),
+
+        let fits = count <= capacity;
+        if fits {
+            for chunk in chunks {
+                chunk_tbl.insert(chunk.hash().into_bytes(), &**chunk)?;
+            }
+        }
+
+        fits
+    };
+
+    if fits {
+        write_txn.commit()?;
+    } else {
+        write_txn.abort()?;
     }
+
+    Ok(fits)
 }
 
-pub fn put_chunk(chunk: Chunk) -> Result<()> {
+/// Deletes a chunk, returning whether it was stored.
+pub fn delete_chunk(hash: Hash) -> Result<bool> {
     let write_txn = get_db().begin_write()?;
 
-    write_txn
+    let deleted = write_txn
         .open_table(TABLE_DEF)?
-        .insert(chunk.id().to_bytes_le(), &**chunk)?;
+        .remove(hash.into_bytes())?
+        .is_some();
 
     write_txn.commit()?;
 
-    Ok(())
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redb::Database;
+
+    fn padded(data: &[u8]) -> [u8; Chunk::SIZE] {
+        let mut padded = [0u8; Chunk::SIZE];
+        padded[..data.len()].copy_from_slice(data);
+        padded
+    }
+
+    #[test]
+    fn unpadded_len_keeps_trailing_zeros() {
+        for data in [&b""[..], b"chunk", b"chunk\0\0\0", &[0; 100]] {
+            let len = unpadded_len(Hash::of(data), &padded(data));
+            assert_eq!(len, Some(data.len()));
+        }
+
+        assert_eq!(unpadded_len(Hash::of(b"other"), &padded(b"chunk")), None);
+    }
+
+    #[test]
+    fn migrate_legacy_moves_chunks() -> Result<()> {
+        let db = TempDb::create()?;
+
+        let data = b"legacy chunk\0";
+        let write_txn = db.begin_write()?;
+        write_txn
+            .open_table(LEGACY_TABLE_DEF)?
+            .insert(Hash::of(data).into_bytes(), &padded(data))?;
+        migrate_legacy(&write_txn)?;
+        write_txn.commit()?;
+
+        let read_txn = db.begin_read()?;
+        let chunk_tbl = read_txn.open_table(TABLE_DEF)?;
+        let stored = chunk_tbl.get(Hash::of(data).into_bytes())?.unwrap();
+        assert_eq!(stored.value(), data);
+        assert!(read_txn
+            .list_tables()?
+            .all(|table| table.name() != "chunks"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_legacy_refuses_chunks_stored_by_id() -> Result<()> {
+        let db = TempDb::create()?;
+
+        let write_txn = db.begin_write()?;
+        write_txn
+            .open_table(LEGACY_ID_TABLE_DEF)?
+            .insert(Uuid::new_v4().to_bytes_le(), &padded(b"chunk"))?;
+        assert!(migrate_legacy(&write_txn).is_err());
+
+        Ok(())
+    }
+
+    /// A database in a temporary file, removed once dropped.
+    struct TempDb(Option<Database>, std::path::PathBuf);
+
+    impl TempDb {
+        fn create() -> Result<Self> {
+            let path = std::env::temp_dir().join(format!("shard-test-{}.redb", Uuid::new_v4()));
+            Ok(Self(Some(Database::create(&path)?), path))
+        }
+    }
+
+    impl std::ops::Deref for TempDb {
+        type Target = Database;
+
+        fn deref(&self) -> &Database {
+            self.0.as_ref().unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.0.take();
+            let _ = std::fs::remove_file(&self.1);
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use super::{with_table, with_table_mut, Result};
 use chrono::{DateTime, Utc};
+use lib::noise::Keypair;
 use redb::{AccessGuard, ReadOnlyTable, ReadableTable, Table, TableDefinition};
 use uuid::Uuid;
 
@@ -10,14 +11,19 @@ struct InfoKey;
 impl InfoKey {
     const SHARD_ID: &'static str = "shard_id";
     const STARTED_AT: &'static str = "started_at";
+    const PRIVATE_KEY: &'static str = "private_key";
+    const PUBLIC_KEY: &'static str = "public_key";
 }
 
 pub fn init() -> Result<()> {
     fn init_info_inner(mut info_tbl: Table<&str, &str>) -> Result<()> {
-        // Set `shard_id` (if it has not been set)
-        if info_tbl.get(InfoKey::SHARD_ID)?.is_none() {
-            let new_shard_id_string = Uuid::now_v7().to_string();
-            info_tbl.insert(InfoKey::SHARD_ID, new_shard_id_string.as_str())?;
+        // `shard_id` is left unset until the server assigns one.
+
+        // Generate the static keypair the shard authenticates with, once
+        if info_tbl.get(InfoKey::PRIVATE_KEY)?.is_none() {
+            let (private, public) = Keypair::generate()?.to_hex();
+            info_tbl.insert(InfoKey::PRIVATE_KEY, private.as_str())?;
+            info_tbl.insert(InfoKey::PUBLIC_KEY, public.as_str())?;
         }
 
         // Set `started_at`
@@ -39,12 +45,35 @@ fn get_info<'a, 'b>(info_tbl: &ReadOnlyTable<&str, &str>, key: &str) -> AccessGu
         .expect("info unavailable")
 }
 
-pub fn get_id() -> Uuid {
+/// Returns the ID the server assigned the shard, or `None` if it has never registered.
+pub fn get_id() -> Option<Uuid> {
     with_table(TABLE_DEF, |info_tbl| {
-        let shard_id_str = get_info(&info_tbl, InfoKey::SHARD_ID);
+        let shard_id_str = info_tbl
+            .get(InfoKey::SHARD_ID)
+            .expect("failed to read table")?;
         let shard_id = Uuid::parse_str(shard_id_str.value()).expect("shard id is malformed");
 
-        shard_id
+        Some(shard_id)
+    })
+    .expect("failed to access database")
+}
+
+/// Persists the ID the server assigned the shard, which it presents whenever it reconnects.
+pub fn set_id(id: Uuid) -> Result<()> {
+    with_table_mut(TABLE_DEF, |mut info_tbl| {
+        info_tbl.insert(InfoKey::SHARD_ID, id.to_string().as_str())?;
+
+        Ok(())
+    })?
+}
+
+/// Returns the static keypair the shard authenticates itself to the server with.
+pub fn get_keypair() -> Keypair {
+    with_table(TABLE_DEF, |info_tbl| {
+        let private = get_info(&info_tbl, InfoKey::PRIVATE_KEY);
+        let public = get_info(&info_tbl, InfoKey::PUBLIC_KEY);
+
+        Keypair::from_hex(private.value(), public.value()).expect("keypair is malformed")
     })
     .expect("failed to access database")
 }
@@ -21,6 +21,8 @@ pub fn init() {
     write_txn
         .open_table(chunk::TABLE_DEF)
         .expect("failed to initialize chunk table");
+
+    chunk::migrate_legacy(&write_txn).expect("failed to migrate legacy chunk table");
     write_txn
         .commit()
         .expect("failed to commit table initialization");